@@ -0,0 +1,97 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+
+// Notifications deferred until the outermost batch on this thread completes.
+// Derived states use this to recompute once after every source has been
+// invalidated, which keeps diamond-shaped dependency graphs glitch-free.
+thread_local! {
+    static BATCH: RefCell<Batch> = const {
+        RefCell::new(Batch {
+            depth: 0,
+            pending: VecDeque::new(),
+        })
+    };
+}
+
+type Deferred = Box<dyn FnOnce()>;
+
+struct Batch {
+    depth: usize,
+    // Keyed entries are coalesced: see `defer_once`
+    pending: VecDeque<(Option<usize>, Deferred)>,
+}
+
+// Keeps the depth balanced even if deferred work panics
+struct Depth;
+
+impl Depth {
+    fn enter() -> Self {
+        BATCH.with(|batch| batch.borrow_mut().depth += 1);
+        Depth
+    }
+}
+
+impl Drop for Depth {
+    fn drop(&mut self) {
+        BATCH.with(|batch| batch.borrow_mut().depth -= 1);
+    }
+}
+
+fn depth() -> usize {
+    BATCH.with(|batch| batch.borrow().depth)
+}
+
+// Whether this thread is currently inside a batch, e.g. delivering notifications
+pub(crate) fn is_active() -> bool {
+    depth() > 0
+}
+
+// Runs `f` inside a batch, flushing deferred work once the outermost batch ends
+pub(crate) fn batch<R>(f: impl FnOnce() -> R) -> R {
+    let depth = Depth::enter();
+    let result = f();
+    drop(depth);
+    if self::depth() == 0 {
+        flush();
+    }
+    result
+}
+
+// Queues `f` to run when the current batch ends, or immediately outside one
+pub(crate) fn defer(f: impl FnOnce() + 'static) {
+    BATCH.with(|batch| batch.borrow_mut().pending.push_back((None, Box::new(f))));
+    if depth() == 0 {
+        flush();
+    }
+}
+
+// Like `defer`, but drops `f` if work with the same key is already queued
+pub(crate) fn defer_once(key: usize, f: impl FnOnce() + 'static) {
+    BATCH.with(|batch| {
+        let mut batch = batch.borrow_mut();
+        if !batch.pending.iter().any(|(queued, _)| *queued == Some(key)) {
+            batch.pending.push_back((Some(key), Box::new(f)));
+        }
+    });
+    if depth() == 0 {
+        flush();
+    }
+}
+
+// Drains the queue in FIFO order. Work deferred while draining is appended
+// and picked up by the same loop. The first panic is re-raised only once the
+// queue is empty, so one failing round can't strand the ones after it.
+fn flush() {
+    let depth = Depth::enter();
+    let mut panicked = None;
+    while let Some((_, next)) = BATCH.with(|batch| batch.borrow_mut().pending.pop_front()) {
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(next)) {
+            panicked.get_or_insert(payload);
+        }
+    }
+    drop(depth);
+    if let Some(payload) = panicked {
+        panic::resume_unwind(payload);
+    }
+}
@@ -0,0 +1,379 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::batch;
+use crate::lock;
+use crate::{State, Subscriber, Subscribers, Subscription};
+
+type Invalidate = Arc<dyn Fn() + Send + Sync>;
+
+/// Anything a derived state can read from and watch for changes.
+pub trait Source: Clone + Send + Sync + 'static {
+    type Value: Clone + Send + Sync + 'static;
+
+    /// Returns the current value.
+    fn current(&self) -> Self::Value;
+
+    /// Registers a callback fired whenever the value may have changed.
+    fn on_invalidate(&self, callback: Invalidate) -> Subscription;
+}
+
+impl<T: Clone + Send + Sync + 'static> Source for State<T> {
+    type Value = T;
+
+    fn current(&self) -> T {
+        self.get()
+    }
+
+    fn on_invalidate(&self, callback: Invalidate) -> Subscription {
+        self.subscribe(move |_| callback())
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> Source for Derived<T> {
+    type Value = T;
+
+    fn current(&self) -> T {
+        self.get()
+    }
+
+    fn on_invalidate(&self, callback: Invalidate) -> Subscription {
+        let id = lock::acquire(&self.node.dependents)
+            .insert(Subscriber::Value(Box::new(move |_| callback())));
+        Subscription::new(&self.node.dependents, id)
+    }
+}
+
+/// A tuple of sources feeding a `computed` closure that takes one argument
+/// per source.
+pub trait Sources<F, T>: Send + Sync + 'static {
+    fn compute(&self, f: &F) -> T;
+    fn on_invalidate(&self, callback: Invalidate) -> Vec<Subscription>;
+}
+
+macro_rules! impl_sources {
+    ($($name:ident),+) => {
+        impl<$($name: Source,)+ F, T> Sources<F, T> for ($($name,)+)
+        where
+            F: Fn($(&$name::Value),+) -> T,
+        {
+            #[allow(non_snake_case)]
+            fn compute(&self, f: &F) -> T {
+                let ($($name,)+) = self;
+                f($(&$name.current()),+)
+            }
+
+            #[allow(non_snake_case)]
+            fn on_invalidate(&self, callback: Invalidate) -> Vec<Subscription> {
+                let ($($name,)+) = self;
+                vec![$($name.on_invalidate(callback.clone())),+]
+            }
+        }
+    };
+}
+
+impl_sources!(A);
+impl_sources!(A, B);
+impl_sources!(A, B, C);
+impl_sources!(A, B, C, D);
+impl_sources!(A, B, C, D, E);
+impl_sources!(A, B, C, D, E, G);
+
+/// Read-only state computed from one or more sources. The value is
+/// recomputed lazily after a source changes, and subscribers are notified
+/// once per change even when several paths lead back to the same source.
+pub struct Derived<T> {
+    node: Arc<Node<T>>,
+}
+
+impl<T> Clone for Derived<T> {
+    fn clone(&self) -> Self {
+        Self {
+            node: self.node.clone(),
+        }
+    }
+}
+
+struct Node<T> {
+    cache: Mutex<Cache<T>>,
+    compute: Box<dyn Fn() -> T + Send + Sync>,
+    subscribers: Arc<Mutex<Subscribers<T>>>,
+    dependents: Arc<Mutex<Subscribers<()>>>,
+    scheduled: AtomicBool,
+    // Set for selector-style states that only notify on actual changes
+    distinct: Option<Distinct<T>>,
+    _sources: Vec<Subscription>,
+}
+
+struct Distinct<T> {
+    equals: fn(&T, &T) -> bool,
+    last_notified: Mutex<Option<T>>,
+}
+
+// Cached value tagged with the generation it was computed for, so a
+// recomputation racing with an invalidation never stores a stale value
+struct Cache<T> {
+    generation: u64,
+    value: Option<T>,
+}
+
+/// Builds a derived state from a tuple of sources, e.g.
+/// `computed((a.clone(), b.clone()), |a, b| a + b)`.
+pub fn computed<S, F, T>(sources: S, f: F) -> Derived<T>
+where
+    S: Sources<F, T>,
+    F: Send + Sync + 'static,
+    T: Clone + Send + Sync + 'static,
+{
+    build(sources, f, None)
+}
+
+// Like `computed`, but subscribers only hear about values that differ from
+// the last one they were given
+pub(crate) fn computed_distinct<S, F, T>(sources: S, f: F) -> Derived<T>
+where
+    S: Sources<F, T>,
+    F: Send + Sync + 'static,
+    T: Clone + PartialEq + Send + Sync + 'static,
+{
+    let distinct = Distinct {
+        equals: |a: &T, b: &T| a == b,
+        last_notified: Mutex::new(None),
+    };
+    build(sources, f, Some(distinct))
+}
+
+fn build<S, F, T>(sources: S, f: F, distinct: Option<Distinct<T>>) -> Derived<T>
+where
+    S: Sources<F, T>,
+    F: Send + Sync + 'static,
+    T: Clone + Send + Sync + 'static,
+{
+    let node = Arc::new_cyclic(|weak: &Weak<Node<T>>| {
+        let weak = weak.clone();
+        let subscriptions = sources.on_invalidate(Arc::new(move || {
+            if let Some(node) = weak.upgrade() {
+                Node::invalidate(&node);
+            }
+        }));
+        Node {
+            cache: Mutex::new(Cache {
+                generation: 0,
+                value: None,
+            }),
+            compute: Box::new(move || sources.compute(&f)),
+            subscribers: Arc::new(Mutex::new(Subscribers::new())),
+            dependents: Arc::new(Mutex::new(Subscribers::new())),
+            scheduled: AtomicBool::new(false),
+            distinct,
+            _sources: subscriptions,
+        }
+    });
+    Derived { node }
+}
+
+impl<T: Clone + Send + Sync + 'static> Derived<T> {
+    pub fn get(&self) -> T {
+        self.node.get()
+    }
+
+    pub fn subscribe<F>(&self, callback: F) -> Subscription
+    where
+        F: Fn(&T) + Send + Sync + 'static,
+    {
+        if let Some(distinct) = &self.node.distinct {
+            let mut last_notified = lock::acquire(&distinct.last_notified);
+            if last_notified.is_none() {
+                *last_notified = Some(self.get());
+            }
+        }
+
+        let id =
+            lock::acquire(&self.node.subscribers).insert(Subscriber::Value(Box::new(callback)));
+        Subscription::new(&self.node.subscribers, id)
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        lock::acquire(&self.node.subscribers).callbacks.len()
+    }
+
+    pub fn map<U, F>(&self, f: F) -> Derived<U>
+    where
+        U: Clone + Send + Sync + 'static,
+        F: Fn(&T) -> U + Send + Sync + 'static,
+    {
+        computed((self.clone(),), f)
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> Node<T> {
+    fn get(&self) -> T {
+        let generation = {
+            let cache = lock::acquire(&self.cache);
+            if let Some(value) = &cache.value {
+                return value.clone();
+            }
+            cache.generation
+        };
+
+        let value = (self.compute)();
+        let mut cache = lock::acquire(&self.cache);
+        if cache.generation == generation {
+            cache.value = Some(value.clone());
+        }
+        value
+    }
+
+    // Marks this node and everything downstream dirty right away, but defers
+    // recomputation and subscriber callbacks to the end of the current batch.
+    fn invalidate(node: &Arc<Self>) {
+        {
+            let mut cache = lock::acquire(&node.cache);
+            cache.generation += 1;
+            cache.value = None;
+        }
+
+        Subscribers::for_each(&node.dependents, |dependent| {
+            if let Subscriber::Value(callback) = dependent {
+                callback(&());
+            }
+        });
+
+        let has_subscribers = !lock::acquire(&node.subscribers).callbacks.is_empty();
+        if has_subscribers && !node.scheduled.swap(true, Ordering::SeqCst) {
+            let weak = Arc::downgrade(node);
+            batch::defer(move || {
+                if let Some(node) = weak.upgrade() {
+                    node.scheduled.store(false, Ordering::SeqCst);
+                    node.notify();
+                }
+            });
+        }
+    }
+
+    fn notify(&self) {
+        let current = self.get();
+        if let Some(distinct) = &self.distinct {
+            let mut last_notified = lock::acquire(&distinct.last_notified);
+            if let Some(last) = last_notified.as_ref() {
+                if (distinct.equals)(last, &current) {
+                    return;
+                }
+            }
+            *last_notified = Some(current.clone());
+        }
+
+        Subscribers::for_each(&self.subscribers, |subscriber| {
+            if let Subscriber::Value(callback) = subscriber {
+                callback(&current);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn test_map_recomputes_lazily() {
+        let items = State::new(vec![2, 3, 5]);
+        let computations = Arc::new(AtomicUsize::new(0));
+
+        let counter = computations.clone();
+        let total = items.map(move |items| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            items.iter().sum::<i32>()
+        });
+
+        assert_eq!(computations.load(Ordering::SeqCst), 0);
+        assert_eq!(total.get(), 10);
+        assert_eq!(total.get(), 10);
+        assert_eq!(computations.load(Ordering::SeqCst), 1);
+
+        items.update(|items| items.push(10));
+        items.update(|items| items.push(20));
+        assert_eq!(computations.load(Ordering::SeqCst), 1);
+        assert_eq!(total.get(), 40);
+        assert_eq!(computations.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_computed_notifies_subscribers() {
+        let price = State::new(10);
+        let quantity = State::new(2);
+        let total = computed((price.clone(), quantity.clone()), |price, quantity| {
+            price * quantity
+        });
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let _subscription = total.subscribe(move |total| sink.lock().unwrap().push(*total));
+
+        price.set(20);
+        quantity.set(3);
+        assert_eq!(*seen.lock().unwrap(), vec![40, 60]);
+    }
+
+    #[test]
+    fn test_diamond_is_glitch_free() {
+        let source = State::new(1);
+        let doubled = source.map(|v| v * 2);
+        let incremented = source.map(|v| v + 1);
+
+        let computations = Arc::new(AtomicUsize::new(0));
+        let counter = computations.clone();
+        let sum = computed((doubled, incremented), move |doubled, incremented| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            doubled + incremented
+        });
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let _subscription = sum.subscribe(move |sum| sink.lock().unwrap().push(*sum));
+
+        source.set(2);
+        source.set(5);
+
+        // Never observes a half-updated combination such as 4 + 2
+        assert_eq!(*seen.lock().unwrap(), vec![7, 16]);
+        assert_eq!(computations.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_dropping_derived_unsubscribes_from_source() {
+        let source = State::new(1);
+        let derived = source.map(|v| v + 1);
+        assert_eq!(source.subscriber_count(), 1);
+
+        drop(derived);
+        assert_eq!(source.subscriber_count(), 0);
+    }
+
+    #[derive(Clone, PartialEq)]
+    struct AppState {
+        user: String,
+        clicks: u32,
+    }
+
+    #[test]
+    fn test_select_notifies_only_on_projection_change() {
+        let app = State::new(AppState {
+            user: String::from("ada"),
+            clicks: 0,
+        });
+        let user = app.select(|app| &app.user);
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let _subscription = user.subscribe(move |user| sink.lock().unwrap().push(user.clone()));
+
+        app.update(|app| app.clicks += 1);
+        app.update(|app| app.user = String::from("grace"));
+        app.update(|app| app.clicks += 1);
+
+        assert_eq!(user.get(), "grace");
+        assert_eq!(*seen.lock().unwrap(), vec![String::from("grace")]);
+    }
+}
@@ -0,0 +1,231 @@
+use std::any::{Any, TypeId};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::lock;
+use crate::{Entry, State, StateManager, Subscription};
+
+type FeedCallback = dyn Fn(&str, &'static str, &dyn Any) + Send + Sync;
+type Formatter = fn(&dyn Any) -> String;
+
+// Callbacks observing every keyed state of a manager. States only forward
+// their changes here once someone subscribes, so managers without a feed
+// add no subscribers to their states.
+#[derive(Default)]
+pub(crate) struct Feed {
+    next_id: u64,
+    callbacks: BTreeMap<u64, Arc<FeedCallback>>,
+    formatters: Arc<RwLock<HashMap<TypeId, Formatter>>>,
+}
+
+impl Feed {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.callbacks.is_empty()
+    }
+
+    fn dispatch(feed: &Mutex<Feed>, key: &str, type_name: &'static str, value: &dyn Any) {
+        let callbacks: Vec<_> = lock::acquire(feed)
+            .callbacks
+            .iter()
+            .map(|(id, callback)| (*id, callback.clone()))
+            .collect();
+        for (id, callback) in callbacks {
+            if lock::acquire(feed).callbacks.contains_key(&id) {
+                callback(key, type_name, value);
+            }
+        }
+    }
+}
+
+// Subscribes the feed to one registered state; stored per entry as a plain
+// function so the entry doesn't need to know `T`
+pub(crate) fn forward<T: Send + Sync + 'static>(
+    state: &(dyn Any + Send + Sync),
+    key: &str,
+    feed: &Arc<Mutex<Feed>>,
+) -> Subscription {
+    let state = state
+        .downcast_ref::<State<T>>()
+        .expect("entry holds the state it was created for");
+    let key = key.to_string();
+    let feed = Arc::downgrade(feed);
+    state.subscribe(move |value: &T| {
+        if let Some(feed) = feed.upgrade() {
+            Feed::dispatch(&feed, &key, std::any::type_name::<T>(), value);
+        }
+    })
+}
+
+fn format_debug<T: Debug + 'static>(value: &dyn Any) -> String {
+    match value.downcast_ref::<T>() {
+        Some(value) => format!("{:?}", value),
+        None => String::new(),
+    }
+}
+
+impl StateManager {
+    /// Calls `callback` with the key, type name and new value after every
+    /// change to any registered state, including ones registered later.
+    /// States from `provide` are reported with their type name as the key.
+    /// Downcast the value to inspect it.
+    pub fn subscribe_all<F>(&self, callback: F) -> Subscription
+    where
+        F: Fn(&str, &'static str, &dyn Any) + Send + Sync + 'static,
+    {
+        let id = {
+            let mut feed = lock::acquire(&self.feed);
+            let id = feed.next_id;
+            feed.next_id += 1;
+            feed.callbacks.insert(id, Arc::new(callback));
+            id
+        };
+
+        for (key, entry) in lock::write(&self.states).iter_mut() {
+            if entry.forwarder.is_none() {
+                entry.forwarder = Some((entry.forward)(&*entry.state, key, &self.feed));
+            }
+        }
+        for entry in lock::write(&self.singletons).values_mut() {
+            if entry.forwarder.is_none() {
+                let key = entry.type_name;
+                entry.forwarder = Some((entry.forward)(&*entry.state, key, &self.feed));
+            }
+        }
+
+        let feed = Arc::downgrade(&self.feed);
+        Subscription::from_fn(move || {
+            if let Some(feed) = feed.upgrade() {
+                lock::acquire(&feed).callbacks.remove(&id);
+            }
+        })
+    }
+
+    /// Like `subscribe_all`, but passes the value formatted with `Debug` for
+    /// types enabled through `debug_format`, and `None` for the rest.
+    pub fn subscribe_all_debug<F>(&self, callback: F) -> Subscription
+    where
+        F: Fn(&str, &'static str, Option<&str>) + Send + Sync + 'static,
+    {
+        let formatters = lock::acquire(&self.feed).formatters.clone();
+        self.subscribe_all(move |key, type_name, value| {
+            let format = lock::read(&formatters).get(&value.type_id()).copied();
+            let formatted = format.map(|format| format(value));
+            callback(key, type_name, formatted.as_deref());
+        })
+    }
+
+    /// Formats states of type `T` with `Debug` in `subscribe_all_debug`.
+    pub fn debug_format<T: Debug + Send + Sync + 'static>(&self) {
+        let formatters = lock::acquire(&self.feed).formatters.clone();
+        lock::write(&formatters).insert(TypeId::of::<T>(), format_debug::<T>);
+    }
+
+    // Builds the registry entry for a new state, already forwarding to the
+    // feed if anyone is listening
+    pub(crate) fn entry<T: Send + Sync + 'static>(&self, key: &str, state: &State<T>) -> Entry {
+        let mut entry = Entry::new(state);
+        if !lock::acquire(&self.feed).is_empty() {
+            entry.forwarder = Some(forward::<T>(&*entry.state, key, &self.feed));
+        }
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone)]
+    struct Session {
+        user: &'static str,
+    }
+
+    #[test]
+    fn test_subscribe_all_sees_every_state() {
+        let manager = StateManager::new();
+        let count = manager.register("count", 0).unwrap();
+        assert_eq!(count.subscriber_count(), 0);
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink = log.clone();
+        let subscription = manager.subscribe_all(move |key, type_name, value| {
+            let value = match value.downcast_ref::<i32>() {
+                Some(count) => count.to_string(),
+                None => value.downcast_ref::<Session>().unwrap().user.to_string(),
+            };
+            sink.lock()
+                .unwrap()
+                .push(format!("{} ({}) = {}", key, type_name, value));
+        });
+
+        count.set(1);
+        let session = manager
+            .register("session", Session { user: "ada" })
+            .unwrap();
+        session.set(Session { user: "grace" });
+        count.update(|count| *count += 1);
+
+        // Unregistered states drop out of the feed
+        manager.unregister("count");
+        count.set(3);
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "count (i32) = 1",
+                "session (statia::feed::tests::Session) = grace",
+                "count (i32) = 2",
+            ]
+        );
+        drop(subscription);
+        session.set(Session { user: "ada" });
+        assert_eq!(log.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_subscribe_all_sees_provided_states() {
+        let manager = StateManager::new();
+        let session = manager.provide(Session { user: "ada" });
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink = log.clone();
+        let _subscription = manager.subscribe_all(move |key, _, _| {
+            sink.lock().unwrap().push(key.to_string());
+        });
+
+        session.set(Session { user: "grace" });
+        let count = manager.provide(0);
+        count.set(1);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["statia::feed::tests::Session", "i32"]
+        );
+    }
+
+    #[test]
+    fn test_subscribe_all_debug() {
+        let manager = StateManager::new();
+        manager.debug_format::<Session>();
+        let session = manager
+            .register("session", Session { user: "ada" })
+            .unwrap();
+        let count = manager.register("count", 0).unwrap();
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink = log.clone();
+        let _subscription = manager.subscribe_all_debug(move |key, _, value| {
+            sink.lock().unwrap().push(format!("{}: {:?}", key, value));
+        });
+
+        session.set(Session { user: "grace" });
+        count.set(1);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                r#"session: Some("Session { user: \"grace\" }")"#,
+                "count: None",
+            ]
+        );
+    }
+}
@@ -0,0 +1,316 @@
+use std::collections::VecDeque;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+use crate::lock;
+use crate::{State, Subscription};
+
+/// State with undo/redo. Every change made through the wrapped state, whether
+/// by `set`, `update` or a committed transaction, records the previous value.
+/// Moving through history goes through `set`, so subscribers see it as well.
+#[derive(Clone)]
+pub struct HistoryState<T> {
+    state: State<T>,
+    history: Arc<Mutex<History<T>>>,
+    _recorder: Arc<Subscription>,
+}
+
+struct History<T> {
+    undo: VecDeque<T>,
+    redo: Vec<T>,
+    limit: usize,
+    coalesce: Option<Duration>,
+    last_change: Option<Instant>,
+    // Threads whose undo/redo write has yet to be seen by the recorder. A
+    // write is always delivered on the thread that made it, so a change from
+    // another thread landing in between can't be mistaken for it.
+    traveling: Vec<ThreadId>,
+}
+
+impl<T> History<T> {
+    fn record(&mut self, previous: T) {
+        let thread = thread::current().id();
+        if let Some(index) = self.traveling.iter().position(|id| *id == thread) {
+            self.traveling.swap_remove(index);
+            return;
+        }
+
+        let now = Instant::now();
+        let coalesced = match (self.coalesce, self.last_change) {
+            (Some(window), Some(last)) => now.duration_since(last) <= window,
+            _ => false,
+        };
+        self.last_change = Some(now);
+        self.redo.clear();
+
+        if !coalesced && self.limit > 0 {
+            if self.undo.len() == self.limit {
+                self.undo.pop_front();
+            }
+            self.undo.push_back(previous);
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> HistoryState<T> {
+    /// Creates a state keeping up to `limit` undo steps.
+    pub fn new(initial: T, limit: usize) -> Self {
+        Self::from_state(State::new(initial), limit)
+    }
+
+    fn from_state(state: State<T>, limit: usize) -> Self {
+        let history = Arc::new(Mutex::new(History {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            limit,
+            coalesce: None,
+            last_change: None,
+            traveling: Vec::new(),
+        }));
+
+        let recorder = history.clone();
+        let subscription = state.subscribe_change(move |previous, _| {
+            lock::acquire(&recorder).record(previous.clone());
+        });
+
+        Self {
+            state,
+            history,
+            _recorder: Arc::new(subscription),
+        }
+    }
+
+    /// Folds changes arriving less than `window` apart into a single undo step.
+    pub fn with_coalescing(self, window: Duration) -> Self {
+        lock::acquire(&self.history).coalesce = Some(window);
+        self
+    }
+
+    pub fn state(&self) -> &State<T> {
+        &self.state
+    }
+
+    /// Restores the previous value. Returns false if there was nothing to
+    /// undo, or if middleware vetoed it; history is left as it was then.
+    pub fn undo(&self) -> bool {
+        let mut history = lock::acquire(&self.history);
+        let Some(previous) = history.undo.pop_back() else {
+            return false;
+        };
+        history.redo.push(self.state.get());
+        if self.travel(history, previous.clone()) {
+            return true;
+        }
+        let mut history = lock::acquire(&self.history);
+        history.redo.pop();
+        history.undo.push_back(previous);
+        false
+    }
+
+    /// Reapplies the last undone value. Returns false if there was nothing to
+    /// redo, or if middleware vetoed it; history is left as it was then.
+    pub fn redo(&self) -> bool {
+        let mut history = lock::acquire(&self.history);
+        let Some(next) = history.redo.pop() else {
+            return false;
+        };
+        let current = self.state.get();
+        history.undo.push_back(current);
+        if self.travel(history, next.clone()) {
+            return true;
+        }
+        let mut history = lock::acquire(&self.history);
+        history.undo.pop_back();
+        history.redo.push(next);
+        false
+    }
+
+    // Returns false if middleware vetoed the change
+    fn travel(&self, mut history: MutexGuard<'_, History<T>>, value: T) -> bool {
+        let thread = thread::current().id();
+        history.traveling.push(thread);
+        history.last_change = None;
+        drop(history);
+        if self.state.put(value, false).is_ok() {
+            return true;
+        }
+        // The change was never delivered, so nothing will consume the mark
+        let mut history = lock::acquire(&self.history);
+        let index = history.traveling.iter().position(|id| *id == thread);
+        history.traveling.swap_remove(index.expect("marked above"));
+        false
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !lock::acquire(&self.history).undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !lock::acquire(&self.history).redo.is_empty()
+    }
+
+    /// Number of steps that can currently be undone.
+    pub fn history_len(&self) -> usize {
+        lock::acquire(&self.history).undo.len()
+    }
+
+    pub fn clear_history(&self) {
+        let mut history = lock::acquire(&self.history);
+        history.undo.clear();
+        history.redo.clear();
+        history.last_change = None;
+    }
+}
+
+impl<T> Deref for HistoryState<T> {
+    type Target = State<T>;
+
+    fn deref(&self) -> &State<T> {
+        &self.state
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> State<T> {
+    /// Wraps this state with undo/redo history of up to `limit` steps.
+    pub fn with_history(&self, limit: usize) -> HistoryState<T> {
+        HistoryState::from_state(self.clone(), limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Transaction;
+
+    #[test]
+    fn test_undo_redo() {
+        let state = HistoryState::new(0, 10);
+        state.set(1);
+        state.update(|v| *v += 1);
+        let mut transaction = Transaction::new(state.state().clone());
+        transaction.update(|v| *v *= 10);
+        transaction.commit().unwrap();
+
+        assert_eq!(state.get(), 20);
+        assert_eq!(state.history_len(), 3);
+
+        assert!(state.undo());
+        assert_eq!(state.get(), 2);
+        assert!(state.undo());
+        assert!(state.undo());
+        assert_eq!(state.get(), 0);
+        assert!(!state.can_undo());
+        assert!(!state.undo());
+
+        assert!(state.redo());
+        assert!(state.redo());
+        assert_eq!(state.get(), 2);
+
+        // A fresh change discards the redo branch
+        state.set(7);
+        assert!(!state.can_redo());
+        assert!(state.undo());
+        assert_eq!(state.get(), 2);
+    }
+
+    #[test]
+    fn test_history_limit() {
+        let state = State::new(0).with_history(2);
+        for value in 1..=5 {
+            state.set(value);
+        }
+
+        assert_eq!(state.history_len(), 2);
+        assert!(state.undo());
+        assert!(state.undo());
+        assert!(!state.undo());
+        assert_eq!(state.get(), 3);
+    }
+
+    #[test]
+    fn test_history_coalescing() {
+        let state = HistoryState::new(String::new(), 10).with_coalescing(Duration::from_secs(60));
+        for c in "hello".chars() {
+            state.update(|text| text.push(c));
+        }
+
+        assert_eq!(state.history_len(), 1);
+        assert!(state.undo());
+        assert_eq!(state.get(), "");
+    }
+
+    #[test]
+    fn test_vetoed_time_travel() {
+        use crate::{Mutation, Veto};
+
+        let state = HistoryState::new(0, 10);
+        state.set(1);
+        state.set(2);
+        assert!(state.undo());
+        // Only increases are allowed from here on
+        state.add_middleware(|_: Mutation, current: &i32, next: &mut i32| {
+            if *next < *current {
+                return Err(Veto::new("values only grow"));
+            }
+            Ok(())
+        });
+
+        assert!(!state.undo());
+        assert_eq!(state.get(), 1);
+        assert_eq!(state.history_len(), 1);
+        assert!(state.can_redo());
+
+        assert!(state.redo());
+        assert_eq!(state.get(), 2);
+        assert!(!state.undo());
+        assert!(!state.can_redo());
+
+        // The failed undo doesn't swallow the next real change
+        state.set(5);
+        assert_eq!(state.history_len(), 3);
+    }
+
+    #[test]
+    fn test_change_during_time_travel_is_recorded() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        // Another thread writes after the undo lands but before the history
+        // sees it
+        let plain = State::new(0);
+        let armed = Arc::new(AtomicBool::new(false));
+        let trigger = armed.clone();
+        let handle = plain.clone();
+        let _interloper = plain.subscribe(move |_| {
+            if trigger.swap(false, Ordering::SeqCst) {
+                let other = handle.clone();
+                thread::spawn(move || other.set(5)).join().unwrap();
+            }
+        });
+        let state = plain.with_history(10);
+        state.set(1);
+        state.set(2);
+
+        armed.store(true, Ordering::SeqCst);
+        assert!(state.undo());
+        assert_eq!(state.get(), 5);
+        assert!(!state.can_redo());
+        assert!(state.undo());
+        assert_eq!(state.get(), 1);
+    }
+
+    #[test]
+    fn test_time_travel_notifies_subscribers() {
+        let state = HistoryState::new(0, 10);
+        state.set(1);
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let _subscription = state.subscribe(move |value| sink.lock().unwrap().push(*value));
+
+        state.undo();
+        state.redo();
+        assert_eq!(*seen.lock().unwrap(), vec![0, 1]);
+    }
+}
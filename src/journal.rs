@@ -0,0 +1,401 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::lock;
+use crate::persist::Persist;
+
+const LOG: &str = "journal.log";
+const SNAPSHOT: &str = "snapshot";
+
+/// When the journal forces appended records to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// After every record; nothing acknowledged is lost on a crash.
+    Always,
+    /// On the first append once the interval has passed since the last sync.
+    /// There is no background timer: records appended after that stay
+    /// unsynced until the next append, `sync`, `StateManager::flush` or
+    /// drop, so flush explicitly when writes go quiet.
+    Interval(Duration),
+    /// Left to the operating system.
+    Never,
+}
+
+/// Append-only write-ahead log of state changes, usable as a `Persist`
+/// backend. Every save appends a record with the state's version; opening
+/// the journal replays the last snapshot plus the log, keeping the newest
+/// version of each key. A torn record at the end of the log, as left by a
+/// crash mid-write, is dropped.
+pub struct Journal {
+    dir: PathBuf,
+    sync: SyncPolicy,
+    compact_after: Option<usize>,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    log: File,
+    // Latest version and encoded value of every key
+    entries: HashMap<String, (u64, Vec<u8>)>,
+    // Records appended since the last compaction
+    records: usize,
+    last_sync: Instant,
+    unsynced: bool,
+}
+
+impl Journal {
+    /// Opens or creates a journal in `dir` and replays it.
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+
+        let mut entries = HashMap::new();
+        if let Some(mut snapshot) = open_existing(&dir.join(SNAPSHOT))? {
+            replay(&mut snapshot, &mut entries)?;
+        }
+        let mut log = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(dir.join(LOG))?;
+        let (records, valid) = replay(&mut log, &mut entries)?;
+        if valid < log.metadata()?.len() {
+            log.set_len(valid)?;
+            log.sync_all()?;
+        }
+
+        Ok(Self {
+            dir,
+            sync: SyncPolicy::Always,
+            compact_after: None,
+            inner: Mutex::new(Inner {
+                log,
+                entries,
+                records,
+                last_sync: Instant::now(),
+                unsynced: false,
+            }),
+        })
+    }
+
+    pub fn with_sync(mut self, sync: SyncPolicy) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Compacts automatically once the log holds `records` records. This is
+    /// the only automatic trigger; for a time-based schedule, call `compact`
+    /// from a timer of your own.
+    pub fn with_compaction(mut self, records: usize) -> Self {
+        self.compact_after = Some(records);
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Number of records appended since the last compaction.
+    pub fn log_len(&self) -> usize {
+        lock::acquire(&self.inner).records
+    }
+
+    /// Appends a record for `key` at `version`. Records older than the one
+    /// already stored for the key are still logged but never win on replay.
+    pub fn append(&self, key: &str, version: u64, bytes: &[u8]) -> io::Result<()> {
+        let mut inner = lock::acquire(&self.inner);
+        // A torn record would end replay early and take every record after
+        // it with it, so a failed write is cut off again
+        let start = inner.log.metadata()?.len();
+        if let Err(error) = inner.log.write_all(&encode(key, version, bytes)) {
+            inner.log.set_len(start)?;
+            return Err(error);
+        }
+        inner.records += 1;
+        inner.unsynced = true;
+
+        let due = match self.sync {
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => inner.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        };
+        if due {
+            inner.sync()?;
+        }
+
+        let newer = inner
+            .entries
+            .get(key)
+            .is_none_or(|(stored, _)| version >= *stored);
+        if newer {
+            inner
+                .entries
+                .insert(key.to_string(), (version, bytes.to_vec()));
+        }
+
+        if self
+            .compact_after
+            .is_some_and(|limit| inner.records >= limit)
+        {
+            self.compact_locked(&mut inner)?;
+        }
+        Ok(())
+    }
+
+    /// Forces every appended record to disk.
+    pub fn sync(&self) -> io::Result<()> {
+        lock::acquire(&self.inner).sync()
+    }
+
+    /// Writes the latest value of every key to a fresh snapshot and empties
+    /// the log. A crash part way through leaves either the old or the new
+    /// snapshot, and replay tolerates records present in both.
+    pub fn compact(&self) -> io::Result<()> {
+        let mut inner = lock::acquire(&self.inner);
+        self.compact_locked(&mut inner)
+    }
+
+    fn compact_locked(&self, inner: &mut Inner) -> io::Result<()> {
+        let mut keys: Vec<_> = inner.entries.keys().collect();
+        keys.sort();
+        let mut snapshot = Vec::new();
+        for key in keys {
+            let (version, bytes) = &inner.entries[key];
+            snapshot.extend_from_slice(&encode(key, *version, bytes));
+        }
+
+        let temp = self.dir.join(format!("{}.tmp", SNAPSHOT));
+        let mut file = File::create(&temp)?;
+        file.write_all(&snapshot)?;
+        file.sync_all()?;
+        fs::rename(&temp, self.dir.join(SNAPSHOT))?;
+        #[cfg(unix)]
+        File::open(&self.dir)?.sync_all()?;
+
+        inner.log.set_len(0)?;
+        inner.log.sync_all()?;
+        inner.records = 0;
+        inner.last_sync = Instant::now();
+        inner.unsynced = false;
+        Ok(())
+    }
+}
+
+impl Inner {
+    fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced {
+            self.log.sync_data()?;
+            self.unsynced = false;
+        }
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        let _ = lock::acquire(&self.inner).sync();
+    }
+}
+
+impl Persist for Journal {
+    fn save(&self, key: &str, bytes: &[u8]) -> io::Result<()> {
+        let version = lock::acquire(&self.inner)
+            .entries
+            .get(key)
+            .map_or(0, |(version, _)| version + 1);
+        self.append(key, version, bytes)
+    }
+
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.load_version(key)?.map(|(_, bytes)| bytes))
+    }
+
+    fn save_version(&self, key: &str, version: u64, bytes: &[u8]) -> io::Result<()> {
+        self.append(key, version, bytes)
+    }
+
+    fn load_version(&self, key: &str) -> io::Result<Option<(u64, Vec<u8>)>> {
+        Ok(lock::acquire(&self.inner).entries.get(key).cloned())
+    }
+    fn flush(&self) -> io::Result<()> {
+        self.sync()
+    }
+}
+
+fn open_existing(path: &Path) -> io::Result<Option<File>> {
+    match File::open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+// Record layout, all integers little-endian:
+// key length (u32), key, version (u64), value length (u32), value, checksum
+// (u64) of everything before it
+fn encode(key: &str, version: u64, bytes: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(24 + key.len() + bytes.len());
+    record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    record.extend_from_slice(key.as_bytes());
+    record.extend_from_slice(&version.to_le_bytes());
+    record.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    record.extend_from_slice(bytes);
+    let checksum = checksum(&record);
+    record.extend_from_slice(&checksum.to_le_bytes());
+    record
+}
+
+// Reads records into `entries` until the end of the file or the first torn
+// or corrupt record. Returns the number of records read and the length of
+// the valid prefix.
+fn replay(
+    file: &mut File,
+    entries: &mut HashMap<String, (u64, Vec<u8>)>,
+) -> io::Result<(usize, u64)> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+
+    let mut records = 0;
+    let mut offset = 0;
+    while let Some((key, version, bytes, end)) = decode(&data, offset) {
+        let newer = entries
+            .get(key)
+            .is_none_or(|(stored, _)| version >= *stored);
+        if newer {
+            entries.insert(key.to_string(), (version, bytes.to_vec()));
+        }
+        records += 1;
+        offset = end;
+    }
+    Ok((records, offset as u64))
+}
+
+fn decode(data: &[u8], start: usize) -> Option<(&str, u64, &[u8], usize)> {
+    let mut offset = start;
+    let mut take = |len: usize| {
+        let slice = data.get(offset..offset.checked_add(len)?)?;
+        offset += len;
+        Some(slice)
+    };
+
+    let key_len = u32::from_le_bytes(take(4)?.try_into().ok()?) as usize;
+    let key = std::str::from_utf8(take(key_len)?).ok()?;
+    let version = u64::from_le_bytes(take(8)?.try_into().ok()?);
+    let len = u32::from_le_bytes(take(4)?.try_into().ok()?) as usize;
+    let bytes = take(len)?;
+    let stored = u64::from_le_bytes(take(8)?.try_into().ok()?);
+
+    let body_end = start + 16 + key_len + len;
+    if stored != checksum(&data[start..body_end]) {
+        return None;
+    }
+    Some((key, version, bytes, body_end + 8))
+}
+
+// FNV-1a; catches torn writes, not tampering
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::persist::temp_dir;
+    use crate::{BoxError, StateManager};
+
+    fn encode_u32(value: &u32) -> Result<Vec<u8>, BoxError> {
+        Ok(value.to_le_bytes().to_vec())
+    }
+
+    fn decode_u32(bytes: &[u8]) -> Result<u32, BoxError> {
+        Ok(u32::from_le_bytes(bytes.try_into()?))
+    }
+
+    #[test]
+    fn test_replay_keeps_newest_version_and_drops_torn_tail() {
+        let dir = temp_dir("journal-replay");
+        {
+            let journal = Journal::open(&dir).unwrap();
+            journal.append("a", 1, b"one").unwrap();
+            journal.append("a", 3, b"three").unwrap();
+            // Delivered late by a slower writer
+            journal.append("a", 2, b"two").unwrap();
+            journal.append("b", 1, b"bee").unwrap();
+        }
+
+        // Simulate a crash halfway through the next record
+        let mut log = OpenOptions::new().append(true).open(dir.join(LOG)).unwrap();
+        let torn = encode("a", 4, b"four");
+        log.write_all(&torn[..torn.len() / 2]).unwrap();
+        drop(log);
+
+        let journal = Journal::open(&dir).unwrap();
+        assert_eq!(
+            journal.load_version("a").unwrap(),
+            Some((3, b"three".to_vec()))
+        );
+        assert_eq!(journal.load("b").unwrap(), Some(b"bee".to_vec()));
+        assert_eq!(journal.log_len(), 4);
+
+        journal.append("b", 2, b"bees").unwrap();
+        drop(journal);
+        let journal = Journal::open(&dir).unwrap();
+        assert_eq!(journal.load("b").unwrap(), Some(b"bees".to_vec()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_compaction() {
+        let dir = temp_dir("journal-compaction");
+        let journal = Journal::open(&dir)
+            .unwrap()
+            .with_sync(SyncPolicy::Never)
+            .with_compaction(10);
+        for version in 1..=25 {
+            journal.append("count", version, &[version as u8]).unwrap();
+        }
+        assert_eq!(journal.log_len(), 5);
+
+        journal.compact().unwrap();
+        assert_eq!(journal.log_len(), 0);
+        assert_eq!(fs::metadata(dir.join(LOG)).unwrap().len(), 0);
+        drop(journal);
+
+        let journal = Journal::open(&dir).unwrap();
+        assert_eq!(journal.load_version("count").unwrap(), Some((25, vec![25])));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_journaled_state_resumes_version() {
+        let dir = temp_dir("journal-state");
+        {
+            let manager = StateManager::with_persistence(Journal::open(&dir).unwrap());
+            let count = manager
+                .register_persistent_with("count", 0, encode_u32, decode_u32)
+                .unwrap();
+            count.set(1);
+            count.update(|count| *count += 1);
+            let mut transaction = manager.transaction();
+            transaction.update::<u32, _>("count", |count| *count *= 10);
+            transaction.commit().unwrap();
+        }
+
+        let manager = StateManager::with_persistence(Journal::open(&dir).unwrap());
+        let count = manager
+            .register_persistent_with("count", 0, encode_u32, decode_u32)
+            .unwrap();
+        assert_eq!(count.get_versioned(), (20, 3));
+        count.set(21);
+        assert_eq!(count.version(), 4);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
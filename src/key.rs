@@ -0,0 +1,155 @@
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+
+/// Registry key that carries the state's type, so lookups through it can't
+/// name the wrong type or misspell the key, e.g.
+/// `const COUNT: StateKey<u32> = StateKey::new("count");` and then
+/// `manager.register(&COUNT, 0)`.
+pub struct StateKey<T> {
+    name: &'static str,
+    _type: PhantomData<fn() -> T>,
+}
+
+impl<T> StateKey<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            _type: PhantomData,
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl<T> Clone for StateKey<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for StateKey<T> {}
+
+impl<T> fmt::Debug for StateKey<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "StateKey<{}>({:?})",
+            std::any::type_name::<T>(),
+            self.name
+        )
+    }
+}
+
+/// Anything `StateManager` accepts as the key of a `State<T>`: a plain string,
+/// checked at runtime, or a `StateKey<T>`, checked at compile time.
+pub trait AsKey<T> {
+    fn as_key(&self) -> &str;
+}
+
+impl<T> AsKey<T> for &str {
+    fn as_key(&self) -> &str {
+        self
+    }
+}
+
+impl<T> AsKey<T> for String {
+    fn as_key(&self) -> &str {
+        self
+    }
+}
+
+impl<T> AsKey<T> for &String {
+    fn as_key(&self) -> &str {
+        self
+    }
+}
+
+impl<T> AsKey<T> for &StateKey<T> {
+    fn as_key(&self) -> &str {
+        self.name
+    }
+}
+
+/// Why `StateManager::try_get` found no state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GetError {
+    Missing {
+        key: String,
+    },
+    /// The key is registered, but for a different type.
+    TypeMismatch {
+        key: String,
+        expected: &'static str,
+        found: &'static str,
+    },
+}
+
+impl fmt::Display for GetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GetError::Missing { key } => write!(f, "no state registered as `{}`", key),
+            GetError::TypeMismatch {
+                key,
+                expected,
+                found,
+            } => write!(f, "state `{}` holds `{}`, not `{}`", key, found, expected),
+        }
+    }
+}
+
+impl Error for GetError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StateManager;
+
+    const COUNT: StateKey<u32> = StateKey::new("count");
+    const NAME: StateKey<String> = StateKey::new("name");
+
+    #[test]
+    fn test_typed_keys() {
+        let manager = StateManager::new();
+        let count = manager.register(&COUNT, 1).unwrap();
+        manager.register(&NAME, String::from("ada")).unwrap();
+
+        count.set(2);
+        assert_eq!(manager.get(&COUNT).unwrap().get(), 2);
+        assert_eq!(manager.get(&NAME).unwrap().get(), "ada");
+
+        let mut transaction = manager.transaction();
+        transaction.update(&COUNT, |count| *count += 1);
+        transaction.commit().unwrap();
+        assert_eq!(count.get(), 3);
+
+        // String keys still work and name the same state
+        assert_eq!(manager.get::<u32>("count").unwrap().get(), 3);
+    }
+
+    #[test]
+    fn test_try_get_explains_failures() {
+        let manager = StateManager::new();
+        manager.register("count", 1u32).unwrap();
+
+        assert_eq!(
+            manager.try_get::<u32>("missing").err(),
+            Some(GetError::Missing {
+                key: String::from("missing"),
+            })
+        );
+        let error = manager.try_get::<i64>("count").err().unwrap();
+        assert_eq!(
+            error,
+            GetError::TypeMismatch {
+                key: String::from("count"),
+                expected: "i64",
+                found: "u32",
+            }
+        );
+        assert_eq!(error.to_string(), "state `count` holds `u32`, not `i64`");
+        assert!(manager.try_get::<u32>("count").is_ok());
+    }
+}
@@ -1,58 +1,787 @@
-use std::sync::{Arc, Mutex, RwLock};
-use std::collections::HashMap;
-use std::any::Any;
+use std::any::{Any, TypeId};
+use std::collections::hash_map::{self, HashMap};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::ops::Deref;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak};
+
+mod batch;
+mod derived;
+mod feed;
+mod history;
+mod journal;
+mod key;
+mod lock;
+mod middleware;
+mod persist;
+#[cfg(feature = "serde")]
+mod snapshot;
+mod store;
+mod stream;
+mod validate;
+mod watch;
+
+pub use derived::{computed, Derived, Source, Sources};
+pub use history::HistoryState;
+pub use journal::{Journal, SyncPolicy};
+pub use key::{AsKey, GetError, StateKey};
+pub use middleware::{Middleware, Mutation, Veto};
+pub use persist::{FileBackend, Persist, PersistError};
+#[cfg(feature = "serde")]
+pub use snapshot::{Snapshot, SnapshotError};
+pub use store::Store;
+pub use stream::{Changed, Changes, Next};
+pub use validate::{ValidatedState, ValidationError};
+pub use watch::Watcher;
+
+use middleware::WriteError;
+
+type Callback<T> = Box<dyn Fn(&T) + Send + Sync>;
+type ChangeCallback<T> = Box<dyn Fn(&T, &T) + Send + Sync>;
+type Cloner<T> = Arc<OnceLock<fn(&T) -> T>>;
+// Write guard plus the previous value, when change subscribers need it
+type UpdateLock<'a, T> = (RwLockWriteGuard<'a, Arc<T>>, Option<Arc<T>>);
 
 // Core state container
-#[derive(Clone)]
+//
+// Poisoning: a panic inside an updater or subscriber never makes the state
+// unusable. The plain accessors recover a poisoned lock and clear the flag,
+// keeping whatever value the panicking updater left behind. The `try_*`
+// variants report `StatiaError::Poisoned` instead, so callers that care can
+// inspect the value before calling `clear_poison`.
+//
+// Re-entrancy: subscribers run without any lock held, so they may read, set,
+// subscribe or unsubscribe on any state, including their own. Subscribers run
+// in subscription order. A mutation made from inside a subscriber is applied
+// immediately, but its notification is queued until the current round has
+// finished. Several mutations of one state inside a round are coalesced into
+// a single queued round that delivers the latest value; change subscribers
+// get the value from before the first of them. Queued rounds run in the order
+// their states were first mutated. Subscribers added during a round only see
+// later changes, and subscribers removed during a round are not called again.
+//
+// Updating in place: `update` mutates the value directly while nothing else
+// references it. Otherwise a state built with `new` copies the value first,
+// so subscribers may update their own state. States built with
+// `new_unclonable` can't: if another thread is still passing the value to
+// subscribers or `with`, `update` waits for it; if the current thread is, the
+// value cannot change under the caller's feet, so `update` panics and
+// `try_update` reports `StatiaError::Borrowed`; use `set` there instead.
 pub struct State<T> {
-    inner: Arc<RwLock<T>>,
-    subscribers: Arc<Mutex<Vec<Box<dyn Fn(&T) + Send + Sync>>>>,
+    // The value sits behind its own Arc so notifications and `with` can share
+    // it without cloning `T` or holding the lock while callbacks run
+    inner: Arc<RwLock<Arc<T>>>,
+    subscribers: Arc<Mutex<Subscribers<T>>>,
+    // Known once a caller proves `T: Clone`, e.g. through `get`
+    cloner: Cloner<T>,
+    // Only bumped with the write lock held, so it always matches the value
+    // seen under the read lock
+    version: Arc<AtomicU64>,
+    middleware: middleware::Chain<T>,
+}
+
+pub(crate) enum Subscriber<T> {
+    Value(Callback<T>),
+    Change(ChangeCallback<T>),
+}
+
+// Subscriber registry keyed by stable ids, kept in subscription order
+pub(crate) struct Subscribers<T> {
+    next_id: u64,
+    pub(crate) callbacks: BTreeMap<u64, Arc<Subscriber<T>>>,
+}
+
+impl<T> Subscribers<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            next_id: 0,
+            callbacks: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, callback: Subscriber<T>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.callbacks.insert(id, Arc::new(callback));
+        id
+    }
+
+    // Calls `f` for every subscriber registered when the round starts, without
+    // holding the registry lock, skipping any removed while the round runs
+    pub(crate) fn for_each(registry: &Mutex<Self>, mut f: impl FnMut(&Subscriber<T>)) {
+        let snapshot: Vec<_> = lock::acquire(registry)
+            .callbacks
+            .iter()
+            .map(|(id, subscriber)| (*id, subscriber.clone()))
+            .collect();
+        for (id, subscriber) in snapshot {
+            let registered = lock::acquire(registry).callbacks.contains_key(&id);
+            if registered {
+                f(&subscriber);
+            }
+        }
+    }
+
+    // Previous values are only worth capturing when someone will read them
+    fn wants_previous(&self) -> bool {
+        self.callbacks
+            .values()
+            .any(|subscriber| matches!(**subscriber, Subscriber::Change(_)))
+    }
+}
+
+/// Handle returned by `State::subscribe`. Dropping it removes the callback;
+/// call `detach` to keep the callback registered for the lifetime of the state.
+#[must_use = "dropping a Subscription immediately unsubscribes the callback"]
+pub struct Subscription {
+    unsubscribe: Option<Box<dyn FnOnce() + Send + Sync>>,
+}
+
+impl Subscription {
+    pub(crate) fn new<T: 'static>(subscribers: &Arc<Mutex<Subscribers<T>>>, id: u64) -> Self {
+        let subscribers: Weak<Mutex<Subscribers<T>>> = Arc::downgrade(subscribers);
+        Self::from_fn(move || {
+            if let Some(subscribers) = subscribers.upgrade() {
+                lock::acquire(&subscribers).callbacks.remove(&id);
+            }
+        })
+    }
+
+    // For registries that don't use `Subscribers`
+    pub(crate) fn from_fn(unsubscribe: impl FnOnce() + Send + Sync + 'static) -> Self {
+        Self {
+            unsubscribe: Some(Box::new(unsubscribe)),
+        }
+    }
+
+    /// Removes the callback from the state it was registered on.
+    pub fn unsubscribe(mut self) {
+        if let Some(unsubscribe) = self.unsubscribe.take() {
+            unsubscribe();
+        }
+    }
+
+    /// Keeps the callback registered without holding on to the handle.
+    pub fn detach(mut self) {
+        self.unsubscribe = None;
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Some(unsubscribe) = self.unsubscribe.take() {
+            unsubscribe();
+        }
+    }
+}
+
+/// Read guard returned by `State::read`. Holding it blocks writers, so don't
+/// mutate the same state from the thread holding it.
+pub struct StateRef<'a, T> {
+    guard: RwLockReadGuard<'a, Arc<T>>,
+}
+
+impl<T> Deref for StateRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
 }
 
 // State manager that can hold multiple states
 pub struct StateManager {
-    states: RwLock<HashMap<String, Box<dyn Any + Send + Sync>>>,
+    states: RwLock<HashMap<String, Entry>>,
+    // One state per type, separate from the string-keyed states
+    singletons: RwLock<HashMap<TypeId, Entry>>,
+    persistence: Option<persist::Persistence>,
+    events: Arc<Mutex<Subscribers<ManagerEvent>>>,
+    feed: Arc<Mutex<feed::Feed>>,
 }
 
-impl<T: Clone + Send + Sync + 'static> State<T> {
-    pub fn new(initial: T) -> Self {
+/// Change to the set of keys in a `StateManager`. Replacing a key reports
+/// the removal followed by the addition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManagerEvent {
+    Added {
+        key: String,
+        type_name: &'static str,
+    },
+    Removed {
+        key: String,
+        type_name: &'static str,
+    },
+}
+
+// A registered state along with what is known about its type
+struct Entry {
+    state: Box<dyn Any + Send + Sync>,
+    type_name: &'static str,
+    // Subscribes `subscribe_all` listeners to the state, see `feed::forward`
+    forward: fn(&(dyn Any + Send + Sync), &str, &Arc<Mutex<feed::Feed>>) -> Subscription,
+    forwarder: Option<Subscription>,
+    // Set for states registered with serde support
+    #[cfg(feature = "serde")]
+    codec: Option<Box<dyn snapshot::Codec>>,
+}
+
+impl Entry {
+    fn new<T: Send + Sync + 'static>(state: &State<T>) -> Self {
         Self {
-            inner: Arc::new(RwLock::new(initial)),
-            subscribers: Arc::new(Mutex::new(Vec::new())),
+            state: Box::new(state.clone()),
+            type_name: std::any::type_name::<T>(),
+            forward: feed::forward::<T>,
+            forwarder: None,
+            #[cfg(feature = "serde")]
+            codec: None,
         }
     }
+}
 
-    pub fn get(&self) -> T {
-        self.inner.read().unwrap().clone()
+fn downcast<T: Send + Sync + 'static>(key: &str, entry: &Entry) -> Result<State<T>, GetError> {
+    match entry.state.downcast_ref::<State<T>>() {
+        Some(state) => Ok(state.clone()),
+        None => Err(GetError::TypeMismatch {
+            key: key.to_string(),
+            expected: std::any::type_name::<T>(),
+            found: entry.type_name,
+        }),
     }
+}
 
-    pub fn set(&self, new_value: T) {
-        let mut inner = self.inner.write().unwrap();
-        *inner = new_value;
-        drop(inner);
-        
-        // Notify subscribers
-        let subscribers = self.subscribers.lock().unwrap();
-        let current = self.get();
-        for subscriber in subscribers.iter() {
-            subscriber(&current);
+impl<T> Clone for State<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            subscribers: self.subscribers.clone(),
+            cloner: self.cloner.clone(),
+            version: self.version.clone(),
+            middleware: self.middleware.clone(),
+        }
+    }
+}
+
+// Everything except reading out an owned copy works for any `T`, so states
+// can hold handles, buffers or trait objects that don't implement `Clone`
+impl<T: Send + Sync + 'static> State<T> {
+    /// Creates a state for a value that doesn't implement `Clone`. Prefer
+    /// `new` otherwise; see "Updating in place" above for the difference.
+    pub fn new_unclonable(initial: T) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(Arc::new(initial))),
+            subscribers: Arc::new(Mutex::new(Subscribers::new())),
+            cloner: Arc::new(OnceLock::new()),
+            version: Arc::new(AtomicU64::new(0)),
+            middleware: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+
+    // Moves a state recreated from storage to the version it was saved at,
+    // so versions keep increasing across restarts
+    pub(crate) fn at_version(self, version: u64) -> Self {
+        self.version.store(version, Ordering::SeqCst);
+        self
+    }
+
+    /// Borrows the current value without cloning it.
+    pub fn read(&self) -> StateRef<'_, T> {
+        StateRef {
+            guard: lock::read(&self.inner),
         }
     }
 
-    pub fn subscribe<F>(&self, callback: F)
+    /// Runs `f` against the current value without cloning it. The lock is not
+    /// held while `f` runs, so it may read or `set` this state.
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let value = Arc::clone(&lock::read(&self.inner));
+        let _borrow = lock::Borrow::new(&self.inner);
+        f(&value)
+    }
+
+    /// Replaces the value. A change vetoed by middleware is dropped.
+    pub fn set(&self, new_value: T) {
+        // Outside strict mode a veto is the only possible error
+        let _ = self.put(new_value, false);
+    }
+
+    pub fn subscribe<F>(&self, callback: F) -> Subscription
     where
         F: Fn(&T) + Send + Sync + 'static,
     {
-        let mut subscribers = self.subscribers.lock().unwrap();
-        subscribers.push(Box::new(callback));
+        self.add_subscriber(Subscriber::Value(Box::new(callback)))
+    }
+
+    /// Subscribes to a projection of the value; `callback` only runs when the
+    /// selected part changes.
+    pub fn subscribe_with_selector<U, S, F>(&self, selector: S, callback: F) -> Subscription
+    where
+        U: Clone + PartialEq + Send + Sync + 'static,
+        S: Fn(&T) -> &U + Send + Sync + 'static,
+        F: Fn(&U) + Send + Sync + 'static,
+    {
+        let last = Mutex::new(self.with(|value| selector(value).clone()));
+        self.subscribe(move |value| {
+            let selected = selector(value);
+            let mut last = lock::acquire(&last);
+            if *last != *selected {
+                *last = selected.clone();
+                drop(last);
+                callback(selected);
+            }
+        })
+    }
+
+    /// Like `set`, but reports poison and subscriber panics instead of
+    /// panicking. The value is written even if a subscriber panics. Panics in
+    /// the notifications its subscribers trigger on other states are reported
+    /// too. When called from inside a subscriber the notification is queued,
+    /// so panics from it surface in the outer call instead.
+    pub fn try_set(&self, new_value: T) -> Result<(), StatiaError> {
+        Ok(self.put(new_value, true)?)
+    }
+
+    // Shared by `set` and `try_set`; see `modify` for `strict`
+    pub(crate) fn put(&self, mut new_value: T, strict: bool) -> Result<(), WriteError> {
+        let chain = middleware::snapshot(&self.middleware);
+        let inner = if strict {
+            self.inner.write().map_err(|_| StatiaError::Poisoned)?
+        } else {
+            lock::write(&self.inner)
+        };
+        middleware::run(&chain, Mutation::Set, &inner, &mut new_value)?;
+        self.replace(inner, new_value, strict)
+    }
+
+    /// Like `update`, but a panicking updater is caught and reported. The lock
+    /// is not poisoned and subscribers are not notified, though the value keeps
+    /// any changes made before the panic; use a `Transaction` for all-or-nothing.
+    pub fn try_update<F>(&self, updater: F) -> Result<(), StatiaError>
+    where
+        F: FnOnce(&mut T),
+    {
+        let result = self.modify(Mutation::Update, true, |value| {
+            updater(value);
+            Ok(())
+        });
+        Ok(result?)
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.inner.is_poisoned()
+    }
+
+    pub fn clear_poison(&self) {
+        self.inner.clear_poison();
+    }
+
+    /// Number of writes so far. Every `set`, `update` or committed transaction
+    /// increases it, whether or not the value actually differs.
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    /// Writes `new_value` only if the state is still at `expected_version`,
+    /// returning the new version. On a conflict the value is handed back. A
+    /// write vetoed by middleware is handed back too, with `found_version`
+    /// equal to `expected_version`.
+    pub fn compare_and_set(
+        &self,
+        expected_version: u64,
+        mut new_value: T,
+    ) -> Result<u64, Conflict<T>> {
+        let chain = middleware::snapshot(&self.middleware);
+        let mut inner = lock::write(&self.inner);
+        let found_version = self.version();
+        if found_version != expected_version
+            || middleware::run(&chain, Mutation::Set, &inner, &mut new_value).is_err()
+        {
+            return Err(Conflict {
+                value: new_value,
+                expected_version,
+                found_version,
+            });
+        }
+        let current = Arc::new(new_value);
+        let previous = std::mem::replace(&mut *inner, current.clone());
+        let version = self.bump_version();
+        drop(inner);
+
+        self.notify(Some(previous), current);
+        Ok(version)
+    }
+
+    /// Computes a new value from the current one without holding any lock,
+    /// then writes it with `compare_and_set`. Retrying on conflict is left to
+    /// the caller.
+    pub fn fetch_update<F>(&self, f: F) -> Result<u64, Conflict<T>>
+    where
+        F: FnOnce(&T) -> T,
+    {
+        let (value, version) = self.with_version(|value, version| (f(value), version));
+        self.compare_and_set(version, value)
+    }
+
+    // Like `with`, also passing the version the value was written at
+    pub(crate) fn with_version<R>(&self, f: impl FnOnce(&T, u64) -> R) -> R {
+        let (value, version) = {
+            let inner = lock::read(&self.inner);
+            (Arc::clone(&inner), self.version())
+        };
+        let _borrow = lock::Borrow::new(&self.inner);
+        f(&value, version)
+    }
+
+    // Takes the write lock and runs middleware on `value`, so that several
+    // states can be written all-or-nothing, as transactions do
+    #[cfg(feature = "serde")]
+    pub(crate) fn prepare_set(&self, mut value: T) -> Result<Box<dyn Prepared + '_>, Veto> {
+        let chain = middleware::snapshot(&self.middleware);
+        let guard = lock::write(&self.inner);
+        middleware::run(&chain, Mutation::Set, &guard, &mut value)?;
+        Ok(Box::new(PreparedState {
+            state: self,
+            guard,
+            next: Arc::new(value),
+            previous: None,
+        }))
+    }
+
+    // Runs `f` while holding the read lock, so no write can land until it
+    // returns. `f` must not write to this state.
+    pub(crate) fn with_locked<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        let inner = lock::read(&self.inner);
+        f(&inner)
+    }
+
+    // Must be called with the write lock held; returns the new version
+    fn bump_version(&self) -> u64 {
+        self.version.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    fn add_subscriber(&self, subscriber: Subscriber<T>) -> Subscription {
+        let id = lock::acquire(&self.subscribers).insert(subscriber);
+        Subscription::new(&self.subscribers, id)
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        lock::acquire(&self.subscribers).callbacks.len()
     }
 
+    /// Mutates the value in place. Panics if called while this thread is
+    /// still handing the value to subscribers or `with`; see "Updating in
+    /// place" above. A change vetoed by middleware is dropped.
     pub fn update<F>(&self, updater: F)
     where
         F: FnOnce(&mut T),
     {
-        let mut inner = self.inner.write().unwrap();
-        updater(&mut inner);
+        let result = self.modify(Mutation::Update, false, |value| {
+            updater(value);
+            Ok(())
+        });
+        if let Err(WriteError::State(error)) = result {
+            panic!("State::update: {}", error);
+        }
+    }
+
+    // Shared by `update`, `try_update` and `Store::dispatch`. `f` may refuse
+    // the change, but only before touching the value. With middleware, `f`
+    // works on a copy that replaces the value once the chain accepts it. In
+    // strict mode, poison and panics in `f` are reported instead of raised.
+    pub(crate) fn modify<F>(&self, mutation: Mutation, strict: bool, f: F) -> Result<(), WriteError>
+    where
+        F: FnOnce(&mut T) -> Result<(), Veto>,
+    {
+        self.modify_then(mutation, strict, f, |()| {})
+    }
+
+    // Like `modify`, also calling `accepted` with the result of `f` once the
+    // change is certain to land, while the write lock is still held
+    pub(crate) fn modify_then<R, F, C>(
+        &self,
+        mutation: Mutation,
+        strict: bool,
+        f: F,
+        accepted: C,
+    ) -> Result<(), WriteError>
+    where
+        F: FnOnce(&mut T) -> Result<R, Veto>,
+        C: FnOnce(R),
+    {
+        let chain = middleware::snapshot(&self.middleware);
+        let cloner = self.cloner.get().copied();
+        let (mut inner, previous) = match cloner {
+            Some(cloner) if !chain.is_empty() => {
+                let inner = if strict {
+                    self.inner.write().map_err(|_| StatiaError::Poisoned)?
+                } else {
+                    lock::write(&self.inner)
+                };
+                let mut next = cloner(&inner);
+                let result = Self::run_updater(strict, f, &mut next)?;
+                middleware::run(&chain, mutation, &inner, &mut next)?;
+                accepted(result);
+                return self.replace(inner, next, strict);
+            }
+            _ => self.lock_for_update(strict)?,
+        };
+
+        let value = Arc::get_mut(&mut inner).expect("value is unshared after lock_for_update");
+        match Self::run_updater(strict, f, value) {
+            Ok(result) => accepted(result),
+            Err(error) => {
+                // Even a panicking updater may have changed the value
+                if matches!(
+                    error,
+                    WriteError::State(StatiaError::UpdaterPanicked { .. })
+                ) {
+                    self.bump_version();
+                }
+                return Err(error);
+            }
+        }
+        self.bump_version();
+        let current = Arc::clone(&inner);
+        drop(inner);
+
+        if strict {
+            Ok(self.try_notify(previous, current)?)
+        } else {
+            self.notify(previous, current);
+            Ok(())
+        }
+    }
+
+    fn run_updater<R, F>(strict: bool, f: F, value: &mut T) -> Result<R, WriteError>
+    where
+        F: FnOnce(&mut T) -> Result<R, Veto>,
+    {
+        if !strict {
+            return Ok(f(value)?);
+        }
+        match panic::catch_unwind(AssertUnwindSafe(|| f(value))) {
+            Ok(result) => Ok(result?),
+            Err(payload) => Err(WriteError::State(StatiaError::UpdaterPanicked {
+                message: panic_message(payload.as_ref()),
+            })),
+        }
+    }
+
+    // Stores `next` under an already held write lock and notifies
+    fn replace(
+        &self,
+        mut inner: RwLockWriteGuard<'_, Arc<T>>,
+        next: T,
+        strict: bool,
+    ) -> Result<(), WriteError> {
+        let current = Arc::new(next);
+        let previous = std::mem::replace(&mut *inner, current.clone());
+        self.bump_version();
+        drop(inner);
+
+        if strict {
+            Ok(self.try_notify(Some(previous), current)?)
+        } else {
+            self.notify(Some(previous), current);
+            Ok(())
+        }
+    }
+
+    // Takes the write lock once the value can be mutated in place, along with
+    // the previous value when change subscribers need it. A value shared with
+    // another thread is waited for, and copied instead if a cloner is known.
+    fn lock_for_update(&self, strict: bool) -> Result<UpdateLock<'_, T>, StatiaError> {
+        let wants_previous = lock::acquire(&self.subscribers).wants_previous();
+        let cloner = self.cloner.get().copied();
+
+        loop {
+            let mut inner = if strict {
+                self.inner.write().map_err(|_| StatiaError::Poisoned)?
+            } else {
+                lock::write(&self.inner)
+            };
+
+            if let (true, Some(cloner)) = (wants_previous, cloner) {
+                let previous = Arc::clone(&inner);
+                *inner = Arc::new(cloner(&previous));
+                return Ok((inner, Some(previous)));
+            }
+            if Arc::get_mut(&mut inner).is_some() {
+                return Ok((inner, None));
+            }
+            if let Some(cloner) = cloner {
+                let copy = cloner(&inner);
+                *inner = Arc::new(copy);
+                return Ok((inner, None));
+            }
+
+            drop(inner);
+            if lock::Borrow::is_held(&self.inner) {
+                return Err(StatiaError::Borrowed);
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    // Single notification path shared by every mutation. Inside another
+    // notification round the delivery is queued; see "Re-entrancy" above. A
+    // panicking subscriber is re-raised once every other subscriber has run.
+    fn notify(&self, previous: Option<Arc<T>>, current: Arc<T>) {
+        if batch::is_active() {
+            self.queue(previous);
+        } else {
+            self.deliver(previous, current);
+        }
+    }
+
+    fn try_notify(&self, previous: Option<Arc<T>>, current: Arc<T>) -> Result<(), StatiaError> {
+        if batch::is_active() {
+            self.queue(previous);
+            return Ok(());
+        }
+
+        match self.dispatch(previous.as_deref(), &current) {
+            Some(payload) => Err(StatiaError::SubscriberPanicked {
+                message: panic_message(payload.as_ref()),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    // Queues one round per state and batch. Only the first previous value is
+    // kept, and the value is read when the round runs, so nothing in the queue
+    // keeps the live value shared.
+    fn queue(&self, previous: Option<Arc<T>>) {
+        let state = self.clone();
+        batch::defer_once(lock::address(&self.inner), move || {
+            let current = Arc::clone(&lock::read(&state.inner));
+            state.deliver(previous, current);
+        });
+    }
+
+    fn deliver(&self, previous: Option<Arc<T>>, current: Arc<T>) {
+        if let Some(payload) = self.dispatch(previous.as_deref(), &current) {
+            panic::resume_unwind(payload);
+        }
+    }
+
+    // Calls every subscriber, returning the first panic payload if any of them
+    // panicked, or any round they queued on other states. Change subscribers
+    // are skipped when the previous value was not captured.
+    fn dispatch(&self, previous: Option<&T>, current: &T) -> Option<Box<dyn Any + Send>> {
+        let _borrow = lock::Borrow::new(&self.inner);
+        let mut panicked = None;
+        // Rounds queued by the subscribers run as the batch ends
+        let flushed = panic::catch_unwind(AssertUnwindSafe(|| {
+            batch::batch(|| {
+                Subscribers::for_each(&self.subscribers, |subscriber| {
+                    let result =
+                        panic::catch_unwind(AssertUnwindSafe(|| match (subscriber, previous) {
+                            (Subscriber::Value(callback), _) => callback(current),
+                            (Subscriber::Change(callback), Some(previous)) => {
+                                callback(previous, current)
+                            }
+                            (Subscriber::Change(_), None) => {}
+                        }));
+                    if let Err(payload) = result {
+                        panicked.get_or_insert(payload);
+                    }
+                });
+            })
+        }));
+        if let Err(payload) = flushed {
+            panicked.get_or_insert(payload);
+        }
+        panicked
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> State<T> {
+    pub fn new(initial: T) -> Self {
+        let state = Self::new_unclonable(initial);
+        state.cloner.get_or_init(|| T::clone);
+        state
+    }
+
+    pub fn get(&self) -> T {
+        self.cloner.get_or_init(|| T::clone);
+        T::clone(&lock::read(&self.inner))
+    }
+
+    /// Returns the value together with the version it was read at, for a
+    /// later `compare_and_set`.
+    pub fn get_versioned(&self) -> (T, u64) {
+        self.cloner.get_or_init(|| T::clone);
+        let inner = lock::read(&self.inner);
+        (T::clone(&inner), self.version())
+    }
+
+    pub fn try_get(&self) -> Result<T, StatiaError> {
+        self.cloner.get_or_init(|| T::clone);
+        match self.inner.read() {
+            Ok(inner) => Ok(T::clone(&inner)),
+            Err(_) => Err(StatiaError::Poisoned),
+        }
+    }
+
+    /// Subscribes to changes with both the previous and the new value.
+    pub fn subscribe_change<F>(&self, callback: F) -> Subscription
+    where
+        F: Fn(&T, &T) + Send + Sync + 'static,
+    {
+        self.cloner.get_or_init(|| T::clone);
+        self.add_subscriber(Subscriber::Change(Box::new(callback)))
+    }
+
+    /// Derives a read-only state that tracks `f` applied to this value.
+    pub fn map<U, F>(&self, f: F) -> Derived<U>
+    where
+        U: Clone + Send + Sync + 'static,
+        F: Fn(&T) -> U + Send + Sync + 'static,
+    {
+        computed((self.clone(),), f)
+    }
+
+    /// Derives a read-only state holding the projected field. Its subscribers
+    /// are only notified when the projection actually changes.
+    pub fn select<U, F>(&self, selector: F) -> Derived<U>
+    where
+        U: Clone + PartialEq + Send + Sync + 'static,
+        F: Fn(&T) -> &U + Send + Sync + 'static,
+    {
+        derived::computed_distinct((self.clone(),), move |value: &T| selector(value).clone())
+    }
+}
+
+impl<T: PartialEq + Send + Sync + 'static> State<T> {
+    /// Sets the value only if it differs from the current one. Returns whether
+    /// the value changed; subscribers are not notified when it did not.
+    pub fn set_if_changed(&self, mut new_value: T) -> bool {
+        let chain = middleware::snapshot(&self.middleware);
+        let mut inner = lock::write(&self.inner);
+        if **inner == new_value {
+            return false;
+        }
+        // Middleware may veto the value or rewrite it back to the current one
+        let accepted = middleware::run(&chain, Mutation::Set, &inner, &mut new_value).is_ok();
+        if !accepted || **inner == new_value {
+            return false;
+        }
+        let current = Arc::new(new_value);
+        let previous = std::mem::replace(&mut *inner, current.clone());
+        self.bump_version();
+        drop(inner);
+
+        self.notify(Some(previous), current);
+        true
+    }
+}
+
+impl Default for StateManager {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -60,90 +789,1462 @@ impl StateManager {
     pub fn new() -> Self {
         Self {
             states: RwLock::new(HashMap::new()),
+            singletons: RwLock::new(HashMap::new()),
+            persistence: None,
+            events: Arc::new(Mutex::new(Subscribers::new())),
+            feed: Arc::new(Mutex::new(feed::Feed::default())),
         }
     }
 
-    pub fn register<T: Clone + Send + Sync + 'static>(&self, key: &str, initial: T) -> State<T> {
-        let state = State::new(initial);
-        let mut states = self.states.write().unwrap();
-        states.insert(key.to_string(), Box::new(state.clone()));
-        state
+    /// Registers a new state under `key`. Fails if the key is taken; see
+    /// `replace` and `get_or_register`.
+    pub fn register<T: Clone + Send + Sync + 'static>(
+        &self,
+        key: impl AsKey<T>,
+        initial: T,
+    ) -> Result<State<T>, RegisterError> {
+        self.insert(key.as_key(), State::new(initial), false)
     }
 
-    pub fn get<T: Clone + Send + Sync + 'static>(&self, key: &str) -> Option<State<T>> {
-        let states = self.states.read().unwrap();
-        states.get(key)
-            .and_then(|boxed| boxed.downcast_ref::<State<T>>())
-            .cloned()
+    /// Registers an existing state under `key`, e.g. one built with
+    /// `State::new_unclonable`. Fails if the key is taken.
+    pub fn register_state<T: Send + Sync + 'static>(
+        &self,
+        key: impl AsKey<T>,
+        state: State<T>,
+    ) -> Result<State<T>, RegisterError> {
+        self.insert(key.as_key(), state, false)
     }
-}
 
-// Transaction support for atomic updates
-pub struct Transaction<T> {
-    state: State<T>,
-    operations: Vec<Box<dyn FnOnce(&mut T)>>,
-}
+    /// Registers a state under `key`, replacing whatever was registered there.
+    /// Holders of the old state keep it, but it is no longer reachable
+    /// through the manager.
+    pub fn replace<T: Clone + Send + Sync + 'static>(
+        &self,
+        key: impl AsKey<T>,
+        initial: T,
+    ) -> State<T> {
+        self.insert(key.as_key(), State::new(initial), true)
+            .expect("replacing never conflicts")
+    }
 
-impl<T: Clone + Send + Sync + 'static> Transaction<T> {
-    pub fn new(state: State<T>) -> Self {
-        Self {
-            state,
-            operations: Vec::new(),
+    /// Returns the state under `key`, registering one from `init` if the key
+    /// is free. Fails only if the key holds a different type.
+    pub fn get_or_register<T: Clone + Send + Sync + 'static>(
+        &self,
+        key: impl AsKey<T>,
+        init: impl FnOnce() -> T,
+    ) -> Result<State<T>, GetError> {
+        let key = key.as_key();
+        let mut states = lock::write(&self.states);
+        if let Some(entry) = states.get(key) {
+            return downcast(key, entry);
         }
+        let state = State::new(init());
+        states.insert(key.to_string(), self.entry(key, &state));
+        drop(states);
+
+        self.emit(ManagerEvent::Added {
+            key: key.to_string(),
+            type_name: std::any::type_name::<T>(),
+        });
+        Ok(state)
     }
 
-    pub fn update<F>(&mut self, operation: F)
-    where
-        F: FnOnce(&mut T) + 'static,
-    {
-        self.operations.push(Box::new(operation));
+    fn insert<T: Send + Sync + 'static>(
+        &self,
+        key: &str,
+        state: State<T>,
+        replace: bool,
+    ) -> Result<State<T>, RegisterError> {
+        let mut states = lock::write(&self.states);
+        let previous = match states.entry(key.to_string()) {
+            hash_map::Entry::Occupied(mut occupied) if replace => {
+                Some(occupied.insert(self.entry(key, &state)))
+            }
+            hash_map::Entry::Occupied(occupied) => {
+                return Err(RegisterError::Duplicate {
+                    key: key.to_string(),
+                    type_name: occupied.get().type_name,
+                });
+            }
+            hash_map::Entry::Vacant(vacant) => {
+                vacant.insert(self.entry(key, &state));
+                None
+            }
+        };
+        drop(states);
+
+        if let Some(previous) = previous {
+            self.removed(key, previous);
+        }
+        self.emit(ManagerEvent::Added {
+            key: key.to_string(),
+            type_name: std::any::type_name::<T>(),
+        });
+        Ok(state)
     }
 
-    pub fn commit(self) {
-        self.state.update(|value| {
-            for op in self.operations {
-                op(value);
+    /// Removes the state under `key`, returning whether there was one.
+    /// Holders of the state keep it; it just can't be looked up any more.
+    pub fn unregister(&self, key: &str) -> bool {
+        let removed = lock::write(&self.states).remove(key);
+        match removed {
+            Some(entry) => {
+                self.removed(key, entry);
+                true
             }
+            None => false,
+        }
+    }
+
+    fn removed(&self, key: &str, entry: Entry) {
+        if let Some(persistence) = &self.persistence {
+            persistence.forget(key);
+        }
+        self.emit(ManagerEvent::Removed {
+            key: key.to_string(),
+            type_name: entry.type_name,
         });
     }
-}
 
-// Example usage
-#[cfg(test)]
-mod tests {
-    use super::*;
-    
-    #[test]
-    fn test_basic_state() {
-        let state = State::new(0);
-        assert_eq!(state.get(), 0);
-        
-        state.set(42);
-        assert_eq!(state.get(), 42);
+    pub fn contains(&self, key: &str) -> bool {
+        lock::read(&self.states).contains_key(key)
     }
 
-    #[test]
-    fn test_state_manager() {
-        let manager = StateManager::new();
-        let count_state = manager.register("count", 0);
+    /// Every registered key with the type name of its state, sorted by key.
+    pub fn keys(&self) -> impl Iterator<Item = (String, &'static str)> {
+        let mut keys: Vec<_> = lock::read(&self.states)
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.type_name))
+            .collect();
+        keys.sort();
+        keys.into_iter()
+    }
 
-        count_state.set(10);
-        assert_eq!(count_state.get(), 10);
-        
-        let retrieved_count = manager.get::<i32>("count").unwrap();
-        assert_eq!(retrieved_count.get(), 10);
+    /// Calls `callback` whenever a key is registered, replaced or removed.
+    pub fn subscribe_events<F>(&self, callback: F) -> Subscription
+    where
+        F: Fn(&ManagerEvent) + Send + Sync + 'static,
+    {
+        let id = lock::acquire(&self.events).insert(Subscriber::Value(Box::new(callback)));
+        Subscription::new(&self.events, id)
     }
 
-    #[test]
+    // Runs after the registry lock is released, so listeners may use the
+    // manager freely
+    fn emit(&self, event: ManagerEvent) {
+        Subscribers::for_each(&self.events, |subscriber| {
+            if let Subscriber::Value(callback) = subscriber {
+                callback(&event);
+            }
+        });
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self, key: impl AsKey<T>) -> Option<State<T>> {
+        self.try_get(key).ok()
+    }
+
+    /// Like `get`, but tells a missing key apart from one registered with a
+    /// different type.
+    pub fn try_get<T: Send + Sync + 'static>(
+        &self,
+        key: impl AsKey<T>,
+    ) -> Result<State<T>, GetError> {
+        let key = key.as_key();
+        match lock::read(&self.states).get(key) {
+            Some(entry) => downcast(key, entry),
+            None => Err(GetError::Missing {
+                key: key.to_string(),
+            }),
+        }
+    }
+
+    /// Registers the state for type `T`, replacing any earlier one. Singletons
+    /// don't share a namespace with keyed states.
+    pub fn provide<T: Clone + Send + Sync + 'static>(&self, initial: T) -> State<T> {
+        self.provide_state(State::new(initial))
+    }
+
+    /// Like `provide`, but takes an existing state, e.g. one built with
+    /// `State::new_unclonable`.
+    pub fn provide_state<T: Send + Sync + 'static>(&self, state: State<T>) -> State<T> {
+        let entry = self.entry(std::any::type_name::<T>(), &state);
+        lock::write(&self.singletons).insert(TypeId::of::<T>(), entry);
+        state
+    }
+
+    /// Returns the state provided for type `T`.
+    pub fn use_state<T: Send + Sync + 'static>(&self) -> Option<State<T>> {
+        lock::read(&self.singletons)
+            .get(&TypeId::of::<T>())
+            .and_then(|entry| entry.state.downcast_ref::<State<T>>())
+            .cloned()
+    }
+
+    /// Starts a transaction that can update several registered states at once.
+    pub fn transaction(&self) -> ManagerTransaction<'_> {
+        ManagerTransaction {
+            manager: self,
+            staged: Vec::new(),
+            operations: 0,
+            unknown: None,
+        }
+    }
+}
+
+type BoxError = Box<dyn Error + Send + Sync>;
+type Operation<T> = Box<dyn FnOnce(&mut T) -> Result<(), BoxError>>;
+
+// Transaction support for atomic updates. Operations run against a copy of
+// the value, which only replaces the stored one if every operation succeeds.
+// Dropping a transaction without committing discards its operations.
+pub struct Transaction<T> {
+    state: State<T>,
+    operations: Vec<Operation<T>>,
+}
+
+/// Reason a transaction was not applied, with the index of the failing operation.
+#[derive(Debug)]
+pub enum CommitError {
+    Failed {
+        operation: usize,
+        error: BoxError,
+    },
+    Panicked {
+        operation: usize,
+        message: String,
+    },
+    UnknownState {
+        operation: usize,
+        key: String,
+    },
+    /// Middleware refused the result; `operation` is the last one queued for
+    /// the vetoed state.
+    Vetoed {
+        operation: usize,
+        veto: Veto,
+    },
+}
+
+impl CommitError {
+    /// Index of the operation that failed, in the order it was queued.
+    pub fn operation(&self) -> usize {
+        match self {
+            CommitError::Failed { operation, .. }
+            | CommitError::Panicked { operation, .. }
+            | CommitError::UnknownState { operation, .. }
+            | CommitError::Vetoed { operation, .. } => *operation,
+        }
+    }
+}
+
+impl fmt::Display for CommitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommitError::Failed { operation, error } => {
+                write!(f, "transaction operation {} failed: {}", operation, error)
+            }
+            CommitError::Panicked { operation, message } => {
+                write!(
+                    f,
+                    "transaction operation {} panicked: {}",
+                    operation, message
+                )
+            }
+            CommitError::UnknownState { operation, key } => write!(
+                f,
+                "transaction operation {} targets unknown state {:?} or the wrong type",
+                operation, key
+            ),
+            CommitError::Vetoed { operation, veto } => {
+                write!(
+                    f,
+                    "transaction operation {} was vetoed: {}",
+                    operation,
+                    veto.reason()
+                )
+            }
+        }
+    }
+}
+
+impl Error for CommitError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CommitError::Failed { error, .. } => Some(error.as_ref()),
+            CommitError::Vetoed { veto, .. } => Some(veto),
+            CommitError::Panicked { .. } | CommitError::UnknownState { .. } => None,
+        }
+    }
+}
+
+// Runs a queued operation, turning both errors and panics into a CommitError
+fn run_operation<T>(
+    index: usize,
+    operation: Operation<T>,
+    value: &mut T,
+) -> Result<(), CommitError> {
+    match panic::catch_unwind(AssertUnwindSafe(|| operation(value))) {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(error)) => Err(CommitError::Failed {
+            operation: index,
+            error,
+        }),
+        Err(payload) => Err(CommitError::Panicked {
+            operation: index,
+            message: panic_message(payload.as_ref()),
+        }),
+    }
+}
+
+/// Errors reported by the non-panicking `try_*` accessors on `State`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatiaError {
+    /// A previous panic poisoned the lock; see `State::clear_poison`.
+    Poisoned,
+    /// The value is still being handed to subscribers or `with` on this thread.
+    Borrowed,
+    UpdaterPanicked {
+        message: String,
+    },
+    SubscriberPanicked {
+        message: String,
+    },
+    /// Middleware refused the change; see `State::add_middleware`.
+    Vetoed {
+        reason: String,
+    },
+}
+
+impl fmt::Display for StatiaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatiaError::Poisoned => write!(f, "state lock poisoned by an earlier panic"),
+            StatiaError::Borrowed => write!(
+                f,
+                "value is still borrowed by a notification or `with` on this thread"
+            ),
+            StatiaError::UpdaterPanicked { message } => write!(f, "updater panicked: {}", message),
+            StatiaError::SubscriberPanicked { message } => {
+                write!(f, "subscriber panicked: {}", message)
+            }
+            StatiaError::Vetoed { reason } => write!(f, "change vetoed: {}", reason),
+        }
+    }
+}
+
+impl Error for StatiaError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegisterError {
+    /// The key is already registered, holding a state of `type_name`.
+    Duplicate {
+        key: String,
+        type_name: &'static str,
+    },
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterError::Duplicate { key, type_name } => write!(
+                f,
+                "state `{}` is already registered (as `{}`)",
+                key, type_name
+            ),
+        }
+    }
+}
+
+impl Error for RegisterError {}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("unknown panic")
+    }
+}
+
+/// Returned by `compare_and_set` and `fetch_update` when another write got in
+/// first. Carries the rejected value so it can be retried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict<T> {
+    pub value: T,
+    pub expected_version: u64,
+    pub found_version: u64,
+}
+
+impl<T> fmt::Display for Conflict<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "state changed concurrently: expected version {}, found {}",
+            self.expected_version, self.found_version
+        )
+    }
+}
+
+impl<T: fmt::Debug> Error for Conflict<T> {}
+
+impl<T: Clone + Send + Sync + 'static> Transaction<T> {
+    pub fn new(state: State<T>) -> Self {
+        Self {
+            state,
+            operations: Vec::new(),
+        }
+    }
+
+    pub fn update<F>(&mut self, operation: F)
+    where
+        F: FnOnce(&mut T) + 'static,
+    {
+        self.operations.push(Box::new(move |value: &mut T| {
+            operation(value);
+            Ok(())
+        }));
+    }
+
+    /// Queues an operation that can abort the whole transaction by returning an error.
+    pub fn try_update<F, E>(&mut self, operation: F)
+    where
+        F: FnOnce(&mut T) -> Result<(), E> + 'static,
+        E: Into<BoxError>,
+    {
+        self.operations.push(Box::new(move |value: &mut T| {
+            operation(value).map_err(Into::into)
+        }));
+    }
+
+    /// Applies every queued operation, or none of them. Subscribers are
+    /// notified once on success and not at all on failure.
+    pub fn commit(self) -> Result<(), CommitError> {
+        let chain = middleware::snapshot(&self.state.middleware);
+        let last = self.operations.len().saturating_sub(1);
+        let mut inner = lock::write(&self.state.inner);
+        let mut working = T::clone(&inner);
+        for (index, operation) in self.operations.into_iter().enumerate() {
+            run_operation(index, operation, &mut working)?;
+        }
+        middleware::run(&chain, Mutation::Commit, &inner, &mut working).map_err(|veto| {
+            CommitError::Vetoed {
+                operation: last,
+                veto,
+            }
+        })?;
+        let current = Arc::new(working);
+        let previous = std::mem::replace(&mut *inner, current.clone());
+        self.state.bump_version();
+        drop(inner);
+
+        self.state.notify(Some(previous), current);
+        Ok(())
+    }
+
+    /// Discards every queued operation without touching the state.
+    pub fn rollback(self) {}
+}
+
+// Transaction spanning several states registered in a StateManager. Write
+// locks are taken in address order, which is the same for every manager, so
+// concurrent transactions cannot deadlock; a state registered under several
+// keys is locked once. Subscribers only run once every state has been written.
+pub struct ManagerTransaction<'a> {
+    manager: &'a StateManager,
+    staged: Vec<Box<dyn Staged>>,
+    operations: usize,
+    unknown: Option<(usize, String)>,
+}
+
+// Operations queued against one state, erased over the state's type
+trait Staged {
+    fn address(&self) -> usize;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn prepare(&mut self) -> Result<Box<dyn Prepared + '_>, CommitError>;
+}
+
+// A state whose lock is held and whose new value has been computed
+trait Prepared {
+    fn write(&mut self);
+    fn finish(self: Box<Self>) -> Box<dyn FnOnce()>;
+}
+
+struct StagedState<T> {
+    state: State<T>,
+    operations: Vec<(usize, Operation<T>)>,
+}
+
+struct PreparedState<'a, T> {
+    state: &'a State<T>,
+    guard: RwLockWriteGuard<'a, Arc<T>>,
+    next: Arc<T>,
+    previous: Option<Arc<T>>,
+}
+
+impl<T: Clone + Send + Sync + 'static> Staged for StagedState<T> {
+    fn address(&self) -> usize {
+        lock::address(&self.state.inner)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn prepare(&mut self) -> Result<Box<dyn Prepared + '_>, CommitError> {
+        let chain = middleware::snapshot(&self.state.middleware);
+        let last = self.operations.last().map_or(0, |(index, _)| *index);
+        let guard = lock::write(&self.state.inner);
+        let mut working = T::clone(&guard);
+        for (index, operation) in self.operations.drain(..) {
+            run_operation(index, operation, &mut working)?;
+        }
+        middleware::run(&chain, Mutation::Commit, &guard, &mut working).map_err(|veto| {
+            CommitError::Vetoed {
+                operation: last,
+                veto,
+            }
+        })?;
+        Ok(Box::new(PreparedState {
+            state: &self.state,
+            guard,
+            next: Arc::new(working),
+            previous: None,
+        }))
+    }
+}
+
+impl<T: Send + Sync + 'static> Prepared for PreparedState<'_, T> {
+    fn write(&mut self) {
+        if self.previous.is_none() {
+            self.previous = Some(std::mem::replace(&mut *self.guard, self.next.clone()));
+            self.state.bump_version();
+        }
+    }
+
+    fn finish(self: Box<Self>) -> Box<dyn FnOnce()> {
+        let PreparedState {
+            state,
+            guard,
+            next,
+            previous,
+        } = *self;
+        drop(guard);
+
+        let state = state.clone();
+        Box::new(move || state.notify(previous, next))
+    }
+}
+
+impl ManagerTransaction<'_> {
+    pub fn update<T, F>(&mut self, key: impl AsKey<T>, operation: F)
+    where
+        T: Clone + Send + Sync + 'static,
+        F: FnOnce(&mut T) + 'static,
+    {
+        self.stage(
+            key.as_key(),
+            Box::new(move |value: &mut T| {
+                operation(value);
+                Ok(())
+            }),
+        );
+    }
+
+    /// Queues an operation that can abort the whole transaction by returning an error.
+    pub fn try_update<T, F, E>(&mut self, key: impl AsKey<T>, operation: F)
+    where
+        T: Clone + Send + Sync + 'static,
+        F: FnOnce(&mut T) -> Result<(), E> + 'static,
+        E: Into<BoxError>,
+    {
+        self.stage(
+            key.as_key(),
+            Box::new(move |value: &mut T| operation(value).map_err(Into::into)),
+        );
+    }
+
+    fn stage<T: Clone + Send + Sync + 'static>(&mut self, key: &str, operation: Operation<T>) {
+        let index = self.operations;
+        self.operations += 1;
+
+        let Some(state) = self.manager.get::<T>(key) else {
+            return self.record_unknown(index, key);
+        };
+
+        // Keys naming the same state share its staged operations
+        let address = lock::address(&state.inner);
+        let staged = self
+            .staged
+            .iter_mut()
+            .find(|staged| staged.address() == address)
+            .and_then(|staged| staged.as_any_mut().downcast_mut::<StagedState<T>>());
+        match staged {
+            Some(staged) => staged.operations.push((index, operation)),
+            None => self.staged.push(Box::new(StagedState {
+                state,
+                operations: vec![(index, operation)],
+            })),
+        }
+    }
+
+    fn record_unknown(&mut self, index: usize, key: &str) {
+        if self.unknown.is_none() {
+            self.unknown = Some((index, key.to_string()));
+        }
+    }
+
+    /// Applies every queued operation to every state, or none of them.
+    pub fn commit(mut self) -> Result<(), CommitError> {
+        if let Some((operation, key)) = self.unknown.take() {
+            return Err(CommitError::UnknownState { operation, key });
+        }
+
+        self.staged.sort_by_key(|staged| staged.address());
+        let mut prepared = Vec::with_capacity(self.staged.len());
+        for staged in self.staged.iter_mut() {
+            prepared.push(staged.prepare()?);
+        }
+
+        for state in prepared.iter_mut() {
+            state.write();
+        }
+        let notifications: Vec<_> = prepared.into_iter().map(|state| state.finish()).collect();
+
+        batch::batch(|| {
+            for notify in notifications {
+                notify();
+            }
+        });
+        Ok(())
+    }
+
+    /// Discards every queued operation without touching any state.
+    pub fn rollback(self) {}
+}
+
+// Example usage
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_basic_state() {
+        let state = State::new(0);
+        assert_eq!(state.get(), 0);
+
+        state.set(42);
+        assert_eq!(state.get(), 42);
+    }
+
+    #[test]
+    fn test_state_manager() {
+        let manager = StateManager::new();
+        let count_state = manager.register("count", 0).unwrap();
+
+        count_state.set(10);
+        assert_eq!(count_state.get(), 10);
+
+        let retrieved_count = manager.get::<i32>("count").unwrap();
+        assert_eq!(retrieved_count.get(), 10);
+    }
+
+    #[test]
     fn test_transaction() {
         let state = State::new(vec![1, 2, 3]);
         let mut transaction = Transaction::new(state.clone());
-        
+
         transaction.update(|v| v.push(4));
         transaction.update(|v| v.push(5));
-        
+
         assert_eq!(state.get(), vec![1, 2, 3]);
-        transaction.commit();
+        transaction.commit().unwrap();
         assert_eq!(state.get(), vec![1, 2, 3, 4, 5]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_subscription_unsubscribe() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let state = State::new(0);
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        let counter = calls.clone();
+        let subscription = state.subscribe(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(state.subscriber_count(), 1);
+
+        state.set(1);
+        subscription.unsubscribe();
+        state.set(2);
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(state.subscriber_count(), 0);
+    }
+
+    #[test]
+    fn test_subscription_drop_and_detach() {
+        let state = State::new(0);
+
+        {
+            let _subscription = state.subscribe(|_| {});
+            let _other = state.subscribe(|_| {});
+            assert_eq!(state.subscriber_count(), 2);
+        }
+        assert_eq!(state.subscriber_count(), 0);
+
+        state.subscribe(|_| {}).detach();
+        assert_eq!(state.subscriber_count(), 1);
+
+        // Handles outliving their state are harmless
+        let orphan = State::new(0).subscribe(|_| {});
+        drop(orphan);
+    }
+
+    fn record_notifications<T: Clone + Send + Sync + 'static>(
+        state: &State<T>,
+    ) -> (Arc<Mutex<Vec<T>>>, Subscription) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let subscription =
+            state.subscribe(move |value: &T| sink.lock().unwrap().push(value.clone()));
+        (seen, subscription)
+    }
+
+    #[test]
+    fn test_set_notifies_once() {
+        let state = State::new(0);
+        let (seen, _subscription) = record_notifications(&state);
+
+        state.set(7);
+        assert_eq!(*seen.lock().unwrap(), vec![7]);
+    }
+
+    #[test]
+    fn test_update_notifies_once() {
+        let state = State::new(vec![1]);
+        let (seen, _subscription) = record_notifications(&state);
+
+        state.update(|v| v.push(2));
+        assert_eq!(*seen.lock().unwrap(), vec![vec![1, 2]]);
+    }
+
+    #[test]
+    fn test_commit_notifies_once() {
+        let state = State::new(1);
+        let (seen, _subscription) = record_notifications(&state);
+
+        let mut transaction = Transaction::new(state.clone());
+        transaction.update(|v| *v += 1);
+        transaction.update(|v| *v *= 10);
+        assert!(seen.lock().unwrap().is_empty());
+
+        transaction.commit().unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec![20]);
+    }
+
+    #[test]
+    fn test_subscribe_change() {
+        let state = State::new(1);
+        let changes = Arc::new(Mutex::new(Vec::new()));
+
+        let sink = changes.clone();
+        let _subscription = state.subscribe_change(move |old, new| {
+            sink.lock().unwrap().push((*old, *new));
+        });
+
+        state.set(2);
+        state.update(|v| *v += 3);
+        Transaction::new(state.clone()).commit().unwrap();
+
+        assert_eq!(*changes.lock().unwrap(), vec![(1, 2), (2, 5), (5, 5)]);
+    }
+
+    #[test]
+    fn test_set_if_changed() {
+        let state = State::new(String::from("a"));
+        let (seen, _subscription) = record_notifications(&state);
+
+        assert!(!state.set_if_changed(String::from("a")));
+        assert!(state.set_if_changed(String::from("b")));
+        assert!(!state.set_if_changed(String::from("b")));
+
+        assert_eq!(*seen.lock().unwrap(), vec![String::from("b")]);
+    }
+
+    #[test]
+    fn test_subscribe_with_selector() {
+        let state = State::new((String::from("ada"), 0));
+        let (seen, _all) = record_notifications(&state);
+
+        let selected = Arc::new(Mutex::new(Vec::new()));
+        let sink = selected.clone();
+        let _name = state.subscribe_with_selector(
+            |state| &state.0,
+            move |name: &String| sink.lock().unwrap().push(name.clone()),
+        );
+
+        state.update(|state| state.1 += 1);
+        state.update(|state| state.0 = String::from("grace"));
+        state.update(|state| state.1 += 1);
+
+        assert_eq!(seen.lock().unwrap().len(), 3);
+        assert_eq!(*selected.lock().unwrap(), vec![String::from("grace")]);
+    }
+
+    #[test]
+    fn test_transaction_failure_rolls_back() {
+        let state = State::new(vec![1]);
+        let (seen, _subscription) = record_notifications(&state);
+
+        let mut transaction = Transaction::new(state.clone());
+        transaction.update(|v| v.push(2));
+        transaction.try_update(
+            |v: &mut Vec<i32>| {
+                if v.len() > 1 {
+                    Err("too long")
+                } else {
+                    Ok(())
+                }
+            },
+        );
+        transaction.update(|v| v.push(3));
+
+        let error = transaction.commit().unwrap_err();
+        assert_eq!(error.operation(), 1);
+        assert!(matches!(error, CommitError::Failed { .. }));
+        assert_eq!(
+            error.to_string(),
+            "transaction operation 1 failed: too long"
+        );
+        assert_eq!(state.get(), vec![1]);
+        assert!(seen.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_transaction_panic_rolls_back() {
+        let state = State::new(0);
+
+        let mut transaction = Transaction::new(state.clone());
+        transaction.update(|v| *v = 10);
+        transaction.update(|_| panic!("boom"));
+
+        match transaction.commit() {
+            Err(CommitError::Panicked { operation, message }) => {
+                assert_eq!(operation, 1);
+                assert_eq!(message, "boom");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(state.get(), 0);
+
+        // The state is still usable afterwards
+        state.set(1);
+        assert_eq!(state.get(), 1);
+    }
+
+    #[test]
+    fn test_transaction_rollback_and_drop() {
+        let state = State::new(0);
+
+        let mut transaction = Transaction::new(state.clone());
+        transaction.update(|v| *v = 1);
+        transaction.rollback();
+
+        let mut transaction = Transaction::new(state.clone());
+        transaction.update(|v| *v = 2);
+        drop(transaction);
+
+        assert_eq!(state.get(), 0);
+    }
+
+    #[test]
+    fn test_manager_transaction_commits_all_states() {
+        let manager = StateManager::new();
+        let cart = manager.register("cart", Vec::<&str>::new()).unwrap();
+        let inventory = manager.register("inventory", 3).unwrap();
+
+        // Subscribers see every state already written
+        let observed = Arc::new(Mutex::new(Vec::new()));
+        let sink = observed.clone();
+        let stock = inventory.clone();
+        let _subscription = cart.subscribe(move |cart| {
+            sink.lock().unwrap().push((cart.len(), stock.get()));
+        });
+
+        let mut transaction = manager.transaction();
+        transaction.update("inventory", |stock: &mut i32| *stock -= 1);
+        transaction.update("cart", |cart: &mut Vec<&str>| cart.push("apple"));
+        transaction.commit().unwrap();
+
+        assert_eq!(cart.get(), vec!["apple"]);
+        assert_eq!(inventory.get(), 2);
+        assert_eq!(*observed.lock().unwrap(), vec![(1, 2)]);
+    }
+
+    #[test]
+    fn test_manager_transaction_failure_touches_nothing() {
+        let manager = StateManager::new();
+        let cart = manager.register("cart", Vec::<&str>::new()).unwrap();
+        let inventory = manager.register("inventory", 0).unwrap();
+
+        let mut transaction = manager.transaction();
+        transaction.update("cart", |cart: &mut Vec<&str>| cart.push("apple"));
+        transaction.try_update("inventory", |stock: &mut i32| {
+            *stock -= 1;
+            if *stock < 0 {
+                Err("out of stock")
+            } else {
+                Ok(())
+            }
+        });
+
+        let error = transaction.commit().unwrap_err();
+        assert_eq!(error.operation(), 1);
+        assert!(cart.get().is_empty());
+        assert_eq!(inventory.get(), 0);
+    }
+
+    #[test]
+    fn test_manager_transaction_merges_aliased_keys() {
+        let manager = StateManager::new();
+        let count = manager.register("count", 1).unwrap();
+        manager.register_state("alias", count.clone()).unwrap();
+        let (seen, _subscription) = record_notifications(&count);
+
+        let mut transaction = manager.transaction();
+        transaction.update("count", |count: &mut i32| *count += 1);
+        transaction.update("alias", |count: &mut i32| *count *= 10);
+        transaction.commit().unwrap();
+
+        assert_eq!(count.get(), 20);
+        assert_eq!(count.version(), 1);
+        assert_eq!(*seen.lock().unwrap(), vec![20]);
+    }
+
+    #[test]
+    fn test_manager_transactions_share_lock_order() {
+        let (first, second) = (State::new(0), State::new(0));
+        let forward = StateManager::new();
+        forward.register_state("a", first.clone()).unwrap();
+        forward.register_state("b", second.clone()).unwrap();
+        let backward = StateManager::new();
+        backward.register_state("a", second.clone()).unwrap();
+        backward.register_state("b", first.clone()).unwrap();
+
+        let writers: Vec<_> = [forward, backward]
+            .into_iter()
+            .map(|manager| {
+                std::thread::spawn(move || {
+                    for _ in 0..500 {
+                        let mut transaction = manager.transaction();
+                        transaction.update("a", |value: &mut i32| *value += 1);
+                        transaction.update("b", |value: &mut i32| *value += 1);
+                        transaction.commit().unwrap();
+                    }
+                })
+            })
+            .collect();
+        writers
+            .into_iter()
+            .for_each(|writer| writer.join().unwrap());
+        assert_eq!((first.get(), second.get()), (1000, 1000));
+    }
+
+    #[test]
+    fn test_manager_transaction_unknown_state() {
+        let manager = StateManager::new();
+        let count = manager.register("count", 0).unwrap();
+
+        let mut transaction = manager.transaction();
+        transaction.update("count", |count: &mut i32| *count += 1);
+        transaction.update("count", |name: &mut String| name.clear());
+        transaction.update("missing", |count: &mut i32| *count += 1);
+
+        match transaction.commit() {
+            Err(CommitError::UnknownState { operation, key }) => {
+                assert_eq!(operation, 1);
+                assert_eq!(key, "count");
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(count.get(), 0);
+    }
+
+    #[test]
+    fn test_recovers_from_panicking_updater() {
+        let state = State::new(1);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            state.update(|v| {
+                *v = 2;
+                panic!("boom");
+            })
+        }));
+        assert!(result.is_err());
+        assert!(state.is_poisoned());
+        assert_eq!(state.try_get(), Err(StatiaError::Poisoned));
+        assert_eq!(state.try_set(3), Err(StatiaError::Poisoned));
+
+        // Plain accessors recover and clear the poison
+        assert_eq!(state.get(), 2);
+        assert!(!state.is_poisoned());
+        assert_eq!(state.try_get(), Ok(2));
+    }
+
+    #[test]
+    fn test_try_update_catches_panic() {
+        let state = State::new(1);
+        let (seen, _subscription) = record_notifications(&state);
+
+        let result = state.try_update(|_| panic!("boom"));
+        assert_eq!(
+            result,
+            Err(StatiaError::UpdaterPanicked {
+                message: String::from("boom")
+            })
+        );
+        assert!(!state.is_poisoned());
+        assert!(seen.lock().unwrap().is_empty());
+
+        assert_eq!(state.try_update(|v| *v += 1), Ok(()));
+        assert_eq!(*seen.lock().unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn test_panicking_subscriber_does_not_break_state() {
+        let state = State::new(0);
+        let _panicking = state.subscribe(|value| {
+            if *value == 1 {
+                panic!("bad subscriber");
+            }
+        });
+        let (seen, _subscription) = record_notifications(&state);
+
+        assert_eq!(
+            state.try_set(1),
+            Err(StatiaError::SubscriberPanicked {
+                message: String::from("bad subscriber")
+            })
+        );
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| state.set(1))).is_err());
+
+        // Later subscribers still ran and the state keeps working
+        state.set(2);
+        assert_eq!(*seen.lock().unwrap(), vec![1, 1, 2]);
+        assert_eq!(state.try_get(), Ok(2));
+    }
+
+    #[test]
+    fn test_try_set_reports_nested_subscriber_panic() {
+        let (a, b) = (State::new(0), State::new(0));
+        let _panicking = b.subscribe(|_| panic!("bad subscriber"));
+        let nested = b.clone();
+        let _forward = a.subscribe(move |value| nested.set(*value));
+
+        assert_eq!(
+            a.try_set(1),
+            Err(StatiaError::SubscriberPanicked {
+                message: String::from("bad subscriber")
+            })
+        );
+        assert_eq!((a.get(), b.get()), (1, 1));
+        assert!(a.try_update(|value| *value += 1).is_err());
+    }
+
+    #[test]
+    fn test_panicking_round_does_not_strand_queued_rounds() {
+        let (a, b, c) = (State::new(0), State::new(0), State::new(0));
+        let _panicking = b.subscribe(|_| panic!("bad subscriber"));
+        let (seen, _subscription) = record_notifications(&c);
+        let (nested_b, nested_c) = (b.clone(), c.clone());
+        let _forward = a.subscribe(move |value| {
+            nested_b.set(*value);
+            nested_c.set(*value);
+        });
+
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| a.set(1))).is_err());
+        assert_eq!(*seen.lock().unwrap(), vec![1]);
+
+        // Nothing is left queued for the next unrelated write to deliver
+        State::new(0).set(5);
+        assert_eq!(*seen.lock().unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn test_subscriber_can_subscribe_to_own_state() {
+        let state = State::new(0);
+        let added = Arc::new(Mutex::new(Vec::new()));
+
+        let handle = state.clone();
+        let sink = added.clone();
+        let _subscription = state.subscribe(move |_| {
+            sink.lock().unwrap().push(handle.subscribe(|_| {}));
+        });
+
+        state.set(1);
+        assert_eq!(state.subscriber_count(), 2);
+        state.set(2);
+        assert_eq!(state.subscriber_count(), 3);
+    }
+
+    #[test]
+    fn test_nested_set_is_queued_after_current_round() {
+        let state = State::new(0);
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        let sink = log.clone();
+        let _first = state.subscribe(move |value| sink.lock().unwrap().push(("first", *value)));
+
+        // Clamps the value from inside a subscriber
+        let handle = state.clone();
+        let _clamp = state.subscribe(move |value| {
+            if *value > 10 {
+                handle.set(10);
+            }
+        });
+
+        let sink = log.clone();
+        let _last = state.subscribe(move |value| sink.lock().unwrap().push(("last", *value)));
+
+        state.set(15);
+        assert_eq!(state.get(), 10);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![("first", 15), ("last", 15), ("first", 10), ("last", 10)]
+        );
+    }
+
+    #[test]
+    fn test_nested_mutations_are_queued_and_coalesced() {
+        let a = State::new(0);
+        let b = State::new(0);
+        let c = State::new(0);
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        let sink = log.clone();
+        let _b = b.subscribe(move |value| sink.lock().unwrap().push(format!("b={}", value)));
+        let sink = log.clone();
+        let _c = c.subscribe(move |value| sink.lock().unwrap().push(format!("c={}", value)));
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let sink = changes.clone();
+        let _b_change = b.subscribe_change(move |old, new| sink.lock().unwrap().push((*old, *new)));
+
+        let (b_target, c_target) = (b.clone(), c.clone());
+        let _a_first = a.subscribe(move |value| {
+            b_target.set(*value * 2);
+            c_target.update(|c| *c += 1);
+            b_target.update(|b| *b += 1);
+        });
+        let sink = log.clone();
+        let _a_second = a.subscribe(move |value| sink.lock().unwrap().push(format!("a={}", value)));
+
+        a.set(1);
+        assert_eq!(*log.lock().unwrap(), vec!["a=1", "b=3", "c=1"]);
+        assert_eq!(*changes.lock().unwrap(), vec![(0, 3)]);
+    }
+
+    #[test]
+    fn test_unsubscribe_during_round() {
+        let state = State::new(0);
+        let (seen, victim) = record_notifications(&state);
+        let victim = Arc::new(Mutex::new(Some(victim)));
+
+        // Registered after the victim but removes it before its next round
+        let slot = victim.clone();
+        let _killer = state.subscribe(move |_| {
+            slot.lock().unwrap().take();
+        });
+
+        state.set(1);
+        state.set(2);
+        assert_eq!(*seen.lock().unwrap(), vec![1]);
+        assert_eq!(state.subscriber_count(), 1);
+    }
+
+    #[test]
+    fn test_read_and_with_borrow_without_cloning() {
+        let state = State::new(vec![1, 2, 3]);
+
+        assert_eq!(state.read().len(), 3);
+        assert_eq!(state.with(|v| v.iter().sum::<i32>()), 6);
+
+        // `with` releases the lock before running the closure
+        state.with(|v| state.set(v.iter().map(|x| x * 2).collect()));
+        assert_eq!(*state.read(), vec![2, 4, 6]);
+    }
+
+    #[test]
+    fn test_notifications_share_stored_value() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        struct Counted(Arc<AtomicUsize>);
+
+        impl Clone for Counted {
+            fn clone(&self) -> Self {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                Counted(self.0.clone())
+            }
+        }
+
+        let clones = Arc::new(AtomicUsize::new(0));
+        let state = State::new(Counted(clones.clone()));
+        let _subscription = state.subscribe(|_| {});
+
+        state.set(Counted(clones.clone()));
+        state.update(|_| {});
+        state.with(|_| {});
+        let _ = state.read();
+        assert_eq!(clones.load(Ordering::SeqCst), 0);
+    }
+
+    // Deliberately not Clone
+    struct Buffer {
+        bytes: Vec<u8>,
+    }
+
+    #[test]
+    fn test_non_clone_values() {
+        let manager = StateManager::new();
+        let buffer = manager
+            .register_state(
+                "buffer",
+                State::new_unclonable(Buffer { bytes: Vec::new() }),
+            )
+            .unwrap();
+        let (seen, _subscription) = {
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            let sink = seen.clone();
+            let subscription = buffer.subscribe(move |buffer: &Buffer| {
+                sink.lock().unwrap().push(buffer.bytes.len());
+            });
+            (seen, subscription)
+        };
+
+        buffer.update(|buffer| buffer.bytes.extend_from_slice(b"abc"));
+        buffer.set(Buffer { bytes: vec![1] });
+        assert_eq!(*seen.lock().unwrap(), vec![3, 1]);
+
+        let retrieved = manager.get::<Buffer>("buffer").unwrap();
+        assert_eq!(retrieved.read().bytes, vec![1]);
+        assert_eq!(retrieved.with(|buffer| buffer.bytes.len()), 1);
+
+        let handlers: State<Vec<Box<dyn Fn() -> u8 + Send + Sync>>> =
+            State::new_unclonable(Vec::new());
+        handlers.update(|handlers| handlers.push(Box::new(|| 7)));
+        assert_eq!(handlers.with(|handlers| handlers[0]()), 7);
+
+        manager.provide_state(State::new_unclonable(Buffer { bytes: vec![2, 3] }));
+        let provided = manager.use_state::<Buffer>().unwrap();
+        provided.update(|buffer| buffer.bytes.push(4));
+        assert_eq!(provided.read().bytes, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn test_update_of_value_borrowed_by_current_thread() {
+        let state = State::new_unclonable(Buffer { bytes: Vec::new() });
+
+        let handle = state.clone();
+        let result = state.with(|_| handle.try_update(|buffer| buffer.bytes.push(1)));
+        assert_eq!(result, Err(StatiaError::Borrowed));
+
+        // `set` replaces the value instead of mutating it, so it always works
+        let handle = state.clone();
+        state.with(|_| handle.set(Buffer { bytes: vec![2] }));
+        assert_eq!(state.read().bytes, vec![2]);
+
+        // Clone values can be copied instead
+        let counter = State::new(0);
+        assert_eq!(counter.get(), 0);
+        let handle = counter.clone();
+        counter.with(|_| handle.update(|v| *v += 1));
+        assert_eq!(counter.get(), 1);
+    }
+
+    #[test]
+    fn test_subscriber_updates_own_state() {
+        // No `get` beforehand: `new` alone must allow copying the value
+        let state = State::new(0);
+        let handle = state.clone();
+        let _subscription = state.subscribe(move |value| {
+            if *value == 10 {
+                handle.update(|value| *value += 1);
+            }
+        });
+        state.set(10);
+        assert_eq!(state.get(), 11);
+
+        let manager = StateManager::new();
+        let count = manager.register("count", 0).unwrap();
+        let handle = count.clone();
+        let _subscription = count.subscribe(move |value| {
+            if *value == 1 {
+                handle.update(|value| *value *= 5);
+            }
+        });
+        count.set(1);
+        assert_eq!(count.get(), 5);
+    }
+
+    #[test]
+    fn test_update_waits_for_other_threads() {
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::Duration;
+
+        let state = State::new_unclonable(Buffer { bytes: Vec::new() });
+        let (started, wait_started) = mpsc::channel();
+
+        let handle = state.clone();
+        let reader = thread::spawn(move || {
+            handle.with(|buffer| {
+                started.send(()).unwrap();
+                thread::sleep(Duration::from_millis(50));
+                buffer.bytes.len()
+            })
+        });
+
+        wait_started.recv().unwrap();
+        state.update(|buffer| buffer.bytes.push(1));
+        assert_eq!(reader.join().unwrap(), 0);
+        assert_eq!(state.read().bytes, vec![1]);
+    }
+
+    #[test]
+    fn test_versions_and_compare_and_set() {
+        let state = State::new(10);
+        assert_eq!(state.version(), 0);
+        state.set(10);
+        state.update(|v| *v += 1);
+        assert_eq!(state.get_versioned(), (11, 2));
+
+        let (value, version) = state.get_versioned();
+        state.set(0);
+        let conflict = state.compare_and_set(version, value * 2).unwrap_err();
+        assert_eq!(
+            conflict,
+            Conflict {
+                value: 22,
+                expected_version: 2,
+                found_version: 3,
+            }
+        );
+        assert_eq!(state.get(), 0);
+
+        let (notifications, _subscription) = record_notifications(&state);
+        assert_eq!(state.compare_and_set(3, 5), Ok(4));
+        assert_eq!(state.fetch_update(|v| v + 1), Ok(5));
+        assert_eq!(state.get(), 6);
+        assert_eq!(*notifications.lock().unwrap(), vec![5, 6]);
+
+        let mut transaction = Transaction::new(state.clone());
+        transaction.update(|v| *v += 1);
+        transaction.commit().unwrap();
+        assert_eq!(state.version(), 6);
+    }
+
+    #[test]
+    fn test_fetch_update_retry_loop_across_threads() {
+        let state = State::new(0);
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let state = state.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        while state.fetch_update(|v| v + 1).is_err() {}
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(state.get(), 400);
+        assert_eq!(state.version(), 400);
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Config {
+        verbose: bool,
+    }
+
+    #[test]
+    fn test_type_indexed_singletons() {
+        let manager = StateManager::new();
+        assert!(manager.use_state::<Config>().is_none());
+
+        let config = manager.provide(Config { verbose: false });
+        manager.register("config", 1).unwrap();
+        config.update(|config| config.verbose = true);
+
+        assert_eq!(
+            manager.use_state::<Config>().unwrap().get(),
+            Config { verbose: true }
+        );
+        assert_eq!(manager.get::<i32>("config").unwrap().get(), 1);
+        assert!(manager.use_state::<i32>().is_none());
+    }
+
+    #[test]
+    fn test_key_lifecycle() {
+        let manager = StateManager::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let _events =
+            manager.subscribe_events(move |event| sink.lock().unwrap().push(event.clone()));
+
+        let count = manager.register("count", 1).unwrap();
+        assert_eq!(
+            manager.register("count", 2).err(),
+            Some(RegisterError::Duplicate {
+                key: String::from("count"),
+                type_name: "i32",
+            })
+        );
+        assert_eq!(manager.get_or_register("count", || 3).unwrap().get(), 1);
+        assert!(manager.get_or_register("count", String::new).is_err());
+        manager
+            .get_or_register("name", || String::from("ada"))
+            .unwrap();
+
+        assert!(manager.contains("name"));
+        assert_eq!(
+            manager.keys().collect::<Vec<_>>(),
+            vec![
+                (String::from("count"), "i32"),
+                (String::from("name"), "alloc::string::String"),
+            ]
+        );
+
+        let replaced = manager.replace("count", 10);
+        count.set(5);
+        assert_eq!(manager.get::<i32>("count").unwrap().get(), 10);
+        assert_eq!(replaced.get(), 10);
+
+        assert!(manager.unregister("name"));
+        assert!(!manager.unregister("name"));
+        assert!(!manager.contains("name"));
+
+        let added = |key: &str, type_name| ManagerEvent::Added {
+            key: key.to_string(),
+            type_name,
+        };
+        let removed = |key: &str, type_name| ManagerEvent::Removed {
+            key: key.to_string(),
+            type_name,
+        };
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                added("count", "i32"),
+                added("name", "alloc::string::String"),
+                removed("count", "i32"),
+                added("count", "i32"),
+                removed("name", "alloc::string::String"),
+            ]
+        );
+    }
+}
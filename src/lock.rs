@@ -0,0 +1,68 @@
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// Lock helpers implementing the crate's poison policy: a lock poisoned by a
+// panicking updater or subscriber is recovered and its poison flag cleared,
+// so one panic never takes down every later caller. The `try_*` accessors on
+// `State` check for poison before getting here.
+
+pub(crate) fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|poisoned| {
+        lock.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+pub(crate) fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(|poisoned| {
+        lock.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+pub(crate) fn acquire<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| {
+        mutex.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+// Identifies a lock by address, e.g. to key per-state bookkeeping
+pub(crate) fn address<T>(lock: &Arc<T>) -> usize {
+    Arc::as_ptr(lock) as *const () as usize
+}
+
+thread_local! {
+    static BORROWS: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+// Records that the current thread is lending out a state's value, e.g. to
+// subscribers, so an in-place update from the same thread can fail instead of
+// waiting on itself forever
+pub(crate) struct Borrow {
+    address: usize,
+}
+
+impl Borrow {
+    pub(crate) fn new<T>(lock: &Arc<T>) -> Self {
+        let address = address(lock);
+        BORROWS.with(|borrows| borrows.borrow_mut().push(address));
+        Borrow { address }
+    }
+
+    pub(crate) fn is_held<T>(lock: &Arc<T>) -> bool {
+        let address = address(lock);
+        BORROWS.with(|borrows| borrows.borrow().contains(&address))
+    }
+}
+
+impl Drop for Borrow {
+    fn drop(&mut self) {
+        BORROWS.with(|borrows| {
+            let mut borrows = borrows.borrow_mut();
+            if let Some(index) = borrows.iter().rposition(|held| *held == self.address) {
+                borrows.remove(index);
+            }
+        });
+    }
+}
@@ -0,0 +1,274 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+use crate::lock;
+use crate::{BoxError, State, StatiaError, Store};
+
+/// The kind of change a middleware is asked about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mutation {
+    /// `set` and its variants, including `compare_and_set`.
+    Set,
+    Update,
+    /// A committed `Transaction` or `ManagerTransaction`.
+    Commit,
+    /// A `Store::dispatch`.
+    Dispatch,
+}
+
+/// Hook run before a change is applied, in the order middleware was added.
+/// Each one sees `pending` as left by the previous one and may inspect it,
+/// rewrite it, or veto the change, which leaves the value untouched.
+///
+/// For a `State<T>`, `pending` is the proposed new value. For a
+/// `Store<S, A>`, it is the action about to be reduced. `current` is the
+/// value being replaced. Middleware runs while the state's write lock is
+/// held, so it must not access the state it guards except through these
+/// arguments.
+pub trait Middleware<T, P = T>: Send + Sync + 'static {
+    fn handle(&self, mutation: Mutation, current: &T, pending: &mut P) -> Result<(), Veto>;
+}
+
+impl<T, P, F> Middleware<T, P> for F
+where
+    F: Fn(Mutation, &T, &mut P) -> Result<(), Veto> + Send + Sync + 'static,
+{
+    fn handle(&self, mutation: Mutation, current: &T, pending: &mut P) -> Result<(), Veto> {
+        self(mutation, current, pending)
+    }
+}
+
+/// A middleware's refusal of a change.
+#[derive(Debug)]
+pub struct Veto {
+    reason: BoxError,
+}
+
+impl Veto {
+    pub fn new(reason: impl Into<BoxError>) -> Self {
+        Self {
+            reason: reason.into(),
+        }
+    }
+
+    pub fn reason(&self) -> &(dyn Error + Send + Sync + 'static) {
+        self.reason.as_ref()
+    }
+
+    pub fn into_reason(self) -> BoxError {
+        self.reason
+    }
+}
+
+impl fmt::Display for Veto {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "change vetoed: {}", self.reason)
+    }
+}
+
+impl Error for Veto {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.reason.as_ref())
+    }
+}
+
+impl From<Veto> for StatiaError {
+    fn from(veto: Veto) -> Self {
+        StatiaError::Vetoed {
+            reason: veto.reason.to_string(),
+        }
+    }
+}
+
+// Internal write failure that keeps the veto intact for callers that report
+// it with its original reason, such as `ValidatedState`
+pub(crate) enum WriteError {
+    State(StatiaError),
+    Vetoed(Veto),
+}
+
+impl From<StatiaError> for WriteError {
+    fn from(error: StatiaError) -> Self {
+        WriteError::State(error)
+    }
+}
+
+impl From<Veto> for WriteError {
+    fn from(veto: Veto) -> Self {
+        WriteError::Vetoed(veto)
+    }
+}
+
+impl From<WriteError> for StatiaError {
+    fn from(error: WriteError) -> Self {
+        match error {
+            WriteError::State(error) => error,
+            WriteError::Vetoed(veto) => veto.into(),
+        }
+    }
+}
+
+pub(crate) type Chain<T, P = T> = Arc<RwLock<Vec<Arc<dyn Middleware<T, P>>>>>;
+
+// Copies the chain out so middleware may add middleware without deadlocking
+pub(crate) fn snapshot<T: 'static, P: 'static>(
+    chain: &Chain<T, P>,
+) -> Vec<Arc<dyn Middleware<T, P>>> {
+    lock::read(chain).clone()
+}
+
+pub(crate) fn run<T: 'static, P: 'static>(
+    chain: &[Arc<dyn Middleware<T, P>>],
+    mutation: Mutation,
+    current: &T,
+    pending: &mut P,
+) -> Result<(), Veto> {
+    chain
+        .iter()
+        .try_for_each(|middleware| middleware.handle(mutation, current, pending))
+}
+
+impl<T: Clone + Send + Sync + 'static> State<T> {
+    /// Adds `middleware` after any already added. It guards every change
+    /// made through any clone of this state. A vetoed `set` or `update` is
+    /// silently dropped; `try_set` and `try_update` report
+    /// `StatiaError::Vetoed`, and transactions `CommitError::Vetoed`.
+    pub fn add_middleware(&self, middleware: impl Middleware<T>) {
+        // Middleware compares against an unchanged copy, so updates with
+        // middleware always work on a clone
+        self.cloner.get_or_init(|| T::clone);
+        lock::write(&self.middleware).push(Arc::new(middleware));
+    }
+}
+
+impl<S, A> Store<S, A>
+where
+    S: Clone + Send + Sync + 'static,
+    A: Clone + Send + Sync + 'static,
+{
+    /// Adds `middleware` after any already added, to run on every dispatched
+    /// action before the reducer. The log records actions as rewritten by
+    /// middleware, so `replay` doesn't run it again.
+    pub fn add_middleware(&self, middleware: impl Middleware<S, A>) {
+        lock::write(&self.middleware).push(Arc::new(middleware));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommitError, Transaction};
+    use std::sync::Mutex;
+
+    fn non_negative(_: Mutation, _: &i64, pending: &mut i64) -> Result<(), Veto> {
+        if *pending < 0 {
+            return Err(Veto::new("value must not be negative"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_middleware_veto() {
+        let state = State::new(5i64);
+        state.add_middleware(non_negative);
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let _subscription = state.subscribe(move |value| sink.lock().unwrap().push(*value));
+
+        state.set(-1);
+        state.update(|value| *value -= 10);
+        assert_eq!(
+            state.try_update(|value| *value -= 10),
+            Err(StatiaError::Vetoed {
+                reason: String::from("value must not be negative"),
+            })
+        );
+        let mut transaction = Transaction::new(state.clone());
+        transaction.update(|value| *value += 1);
+        transaction.update(|value| *value -= 10);
+        assert!(matches!(
+            transaction.commit(),
+            Err(CommitError::Vetoed { operation: 1, .. })
+        ));
+        assert_eq!(state.version(), 0);
+
+        state.set(3);
+        assert_eq!(state.get(), 3);
+        assert_eq!(*seen.lock().unwrap(), vec![3]);
+    }
+
+    #[test]
+    fn test_middleware_rewrites_in_order() {
+        let state = State::new(0);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let sink = log.clone();
+        state.add_middleware(
+            move |mutation: Mutation, current: &i32, pending: &mut i32| {
+                sink.lock()
+                    .unwrap()
+                    .push(format!("{:?} {} -> {}", mutation, current, pending));
+                Ok(())
+            },
+        );
+        state.add_middleware(|_: Mutation, _: &i32, pending: &mut i32| {
+            *pending = (*pending).min(10);
+            Ok(())
+        });
+
+        state.set(50);
+        state.update(|value| *value -= 4);
+        assert_eq!(state.get(), 6);
+        // The first middleware saw the values before the clamp
+        assert_eq!(*log.lock().unwrap(), vec!["Set 0 -> 50", "Update 10 -> 6"]);
+    }
+
+    #[test]
+    fn test_store_middleware() {
+        fn reduce(total: &mut u32, amount: u32) {
+            *total += amount;
+        }
+
+        let store = Store::new(0, reduce);
+        store.add_middleware(|_: Mutation, total: &u32, amount: &mut u32| {
+            if *total >= 10 {
+                return Err(Veto::new("store is full"));
+            }
+            *amount = (*amount).min(10 - *total);
+            Ok(())
+        });
+
+        store.dispatch(4);
+        store.dispatch(8);
+        let error = store.try_dispatch(1).unwrap_err();
+        assert_eq!(error.to_string(), "change vetoed: store is full");
+        assert_eq!(store.get(), 10);
+        // The log holds the rewritten actions, so replaying reproduces them
+        assert_eq!(store.actions(), vec![4, 6]);
+    }
+
+    #[test]
+    fn test_state_middleware_veto_keeps_store_log() {
+        fn reduce(total: &mut i64, amount: i64) {
+            *total += amount;
+        }
+
+        let store = Store::new(0, reduce);
+        store.state().add_middleware(non_negative);
+
+        store.dispatch(3);
+        store.dispatch(-5);
+        assert!(store.try_dispatch(-5).is_err());
+        assert_eq!(store.get(), 3);
+        assert_eq!(store.actions(), vec![3]);
+
+        store.replay([3, -10]);
+        assert_eq!(store.get(), 3);
+        assert_eq!(store.actions(), vec![3]);
+
+        let reproduction = Store::new(store.base(), reduce);
+        reproduction.replay(store.actions());
+        assert_eq!(reproduction.get(), store.get());
+    }
+}
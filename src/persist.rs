@@ -0,0 +1,467 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::lock;
+use crate::{AsKey, BoxError, RegisterError, State, StateManager, Subscription};
+
+/// Storage for persisted state values, one blob of bytes per registry key.
+pub trait Persist: Send + Sync {
+    fn save(&self, key: &str, bytes: &[u8]) -> io::Result<()>;
+
+    /// Returns `None` if nothing was saved under `key` yet.
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Saves the value written at `version` of the state. Backends that keep
+    /// more than the latest value, like `Journal`, record the version too.
+    fn save_version(&self, key: &str, version: u64, bytes: &[u8]) -> io::Result<()> {
+        let _ = version;
+        self.save(key, bytes)
+    }
+
+    /// Loads the latest value with the version it was saved at, or version 0
+    /// for backends that don't track versions.
+    fn load_version(&self, key: &str) -> io::Result<Option<(u64, Vec<u8>)>> {
+        Ok(self.load(key)?.map(|bytes| (0, bytes)))
+    }
+
+    /// Forces everything saved so far to durable storage. Backends that
+    /// write through on every save have nothing to do.
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Stores each key in its own file inside a directory. Files are written to a
+/// temporary file first and renamed into place, so a crash mid-save leaves
+/// the previous snapshot intact.
+pub struct FileBackend {
+    dir: PathBuf,
+}
+
+// Distinguishes temporary files of concurrent saves to the same key
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+impl FileBackend {
+    /// Uses `dir`, creating it if needed.
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.state", file_name(key)))
+    }
+}
+
+// Keys may contain anything, so everything but a safe set of characters is
+// escaped; this also rules out `..` and path separators
+fn file_name(key: &str) -> String {
+    let mut name = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(byte as char),
+            _ => name.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    name
+}
+
+impl Persist for FileBackend {
+    fn save(&self, key: &str, bytes: &[u8]) -> io::Result<()> {
+        let path = self.path(key);
+        let temp = self.dir.join(format!(
+            ".{}.{}.{}.tmp",
+            file_name(key),
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        let written = File::create(&temp).and_then(|mut file| {
+            file.write_all(bytes)?;
+            file.sync_all()
+        });
+        if let Err(error) = written.and_then(|()| fs::rename(&temp, &path)) {
+            let _ = fs::remove_file(&temp);
+            return Err(error);
+        }
+
+        // Make the rename itself durable
+        #[cfg(unix)]
+        File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+
+    fn load(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(key)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PersistError {
+    /// The manager was created without a backend; see
+    /// `StateManager::with_persistence`.
+    NoBackend {
+        key: String,
+    },
+    Io {
+        key: String,
+        error: io::Error,
+    },
+    /// The value could not be encoded, or the stored bytes decoded.
+    Format {
+        key: String,
+        error: BoxError,
+    },
+    Register(RegisterError),
+}
+
+impl PersistError {
+    pub fn key(&self) -> &str {
+        match self {
+            PersistError::NoBackend { key }
+            | PersistError::Io { key, .. }
+            | PersistError::Format { key, .. }
+            | PersistError::Register(RegisterError::Duplicate { key, .. }) => key,
+        }
+    }
+}
+
+impl fmt::Display for PersistError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PersistError::NoBackend { key } => {
+                write!(f, "cannot persist `{}`: no backend configured", key)
+            }
+            PersistError::Io { key, error } => write!(f, "persisting `{}` failed: {}", key, error),
+            PersistError::Register(error) => error.fmt(f),
+            PersistError::Format { key, error } => {
+                write!(f, "persisted value for `{}` is invalid: {}", key, error)
+            }
+        }
+    }
+}
+
+impl Error for PersistError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PersistError::NoBackend { .. } => None,
+            PersistError::Io { error, .. } => Some(error),
+            PersistError::Format { error, .. } => Some(error.as_ref()),
+            PersistError::Register(error) => Some(error),
+        }
+    }
+}
+
+type Saver = Box<dyn Fn() -> Result<(), PersistError> + Send + Sync>;
+
+// Backend plus what is needed to save every persistent key again
+pub(crate) struct Persistence {
+    backend: Arc<dyn Persist>,
+    savers: Mutex<BTreeMap<String, (Saver, Subscription)>>,
+    // First failure of an automatic save, reported by the next `save`
+    failed: Arc<Mutex<Option<PersistError>>>,
+}
+
+impl Persistence {
+    pub(crate) fn new(backend: Arc<dyn Persist>) -> Self {
+        Self {
+            backend,
+            savers: Mutex::new(BTreeMap::new()),
+            failed: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // Stops saving a key that was unregistered or replaced
+    pub(crate) fn forget(&self, key: &str) {
+        lock::acquire(&self.savers).remove(key);
+    }
+}
+
+impl StateManager {
+    /// Creates a manager whose persistent states are stored in `backend`.
+    pub fn with_persistence(backend: impl Persist + 'static) -> Self {
+        let mut manager = Self::new();
+        manager.persistence = Some(Persistence::new(Arc::new(backend)));
+        manager
+    }
+
+    /// Registers a state that starts from its saved value, or `default` if
+    /// nothing was saved yet, and is saved again after every change. Like
+    /// `register`, fails if the key is taken.
+    pub fn register_persistent_with<T, E, Enc, Dec>(
+        &self,
+        key: impl AsKey<T>,
+        default: T,
+        encode: Enc,
+        decode: Dec,
+    ) -> Result<State<T>, PersistError>
+    where
+        T: Clone + Send + Sync + 'static,
+        E: Into<BoxError>,
+        Enc: Fn(&T) -> Result<Vec<u8>, E> + Send + Sync + 'static,
+        Dec: FnOnce(&[u8]) -> Result<T, E>,
+    {
+        let key = key.as_key();
+        let Some(persistence) = &self.persistence else {
+            return Err(PersistError::NoBackend {
+                key: key.to_string(),
+            });
+        };
+        let io_error = |error| PersistError::Io {
+            key: key.to_string(),
+            error,
+        };
+
+        let state = match persistence.backend.load_version(key).map_err(io_error)? {
+            Some((version, bytes)) => {
+                let initial = decode(&bytes).map_err(|error| PersistError::Format {
+                    key: key.to_string(),
+                    error: error.into(),
+                })?;
+                State::new(initial).at_version(version)
+            }
+            None => State::new(default),
+        };
+        let state = self
+            .insert(key, state, false)
+            .map_err(PersistError::Register)?;
+
+        // Values are read back with their version rather than taken from the
+        // notification, so concurrent writers can't pair a value with the
+        // wrong version
+        let save = Arc::new(store(key, persistence.backend.clone(), encode));
+        let on_change = save.clone();
+        let current = state.clone();
+        let failed = persistence.failed.clone();
+        let subscription = state.subscribe(move |_| {
+            if let Err(error) = current.with_version(|value, version| on_change(value, version)) {
+                lock::acquire(&failed).get_or_insert(error);
+            }
+        });
+        let current = state.clone();
+        let saver: Saver =
+            Box::new(move || current.with_version(|value, version| save(value, version)));
+
+        lock::acquire(&persistence.savers).insert(key.to_string(), (saver, subscription));
+        Ok(state)
+    }
+
+    /// Saves every persistent state now. Also reports the first automatic
+    /// save that failed since the last call.
+    pub fn save(&self) -> Result<(), PersistError> {
+        let Some(persistence) = &self.persistence else {
+            return Ok(());
+        };
+        if let Some(error) = lock::acquire(&persistence.failed).take() {
+            return Err(error);
+        }
+        for (saver, _) in lock::acquire(&persistence.savers).values() {
+            saver()?;
+        }
+        Ok(())
+    }
+
+    /// Forces saved values to durable storage, e.g. a `Journal` with a
+    /// `SyncPolicy::Interval` once writes stop.
+    pub fn flush(&self) -> io::Result<()> {
+        match &self.persistence {
+            Some(persistence) => persistence.backend.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+// Encodes a value and hands it to the backend under `key`. Saves of one key
+// run one at a time, and a value older than the last one saved is dropped,
+// so a slow writer can't leave a stale value behind a newer one.
+fn store<T, E, Enc>(
+    key: &str,
+    backend: Arc<dyn Persist>,
+    encode: Enc,
+) -> impl Fn(&T, u64) -> Result<(), PersistError> + Send + Sync + 'static
+where
+    E: Into<BoxError>,
+    Enc: Fn(&T) -> Result<Vec<u8>, E> + Send + Sync + 'static,
+{
+    let key = key.to_string();
+    let saved = Mutex::new(0);
+    move |value, version| {
+        let mut saved = lock::acquire(&saved);
+        if version < *saved {
+            return Ok(());
+        }
+        let bytes = encode(value).map_err(|error| PersistError::Format {
+            key: key.clone(),
+            error: error.into(),
+        })?;
+        backend
+            .save_version(&key, version, &bytes)
+            .map_err(|error| PersistError::Io {
+                key: key.clone(),
+                error,
+            })?;
+        *saved = version;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl StateManager {
+    /// Like `register_persistent_with`, storing the value as JSON. The state is
+    /// also included in `snapshot`.
+    pub fn register_persistent<T>(
+        &self,
+        key: impl AsKey<T>,
+        default: T,
+    ) -> Result<State<T>, PersistError>
+    where
+        T: Clone + serde::Serialize + serde::de::DeserializeOwned + Send + Sync + 'static,
+    {
+        let key = key.as_key();
+        let state = self.register_persistent_with(
+            key,
+            default,
+            |value: &T| serde_json::to_vec_pretty(value),
+            |bytes: &[u8]| serde_json::from_slice(bytes),
+        )?;
+        self.attach_codec(key, &state, std::any::type_name::<T>());
+        Ok(state)
+    }
+}
+
+// Fresh, empty directory under the system temp dir for file-backed tests
+#[cfg(test)]
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "statia-{}-{}-{}",
+        name,
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(value: &u32) -> Result<Vec<u8>, BoxError> {
+        Ok(value.to_string().into_bytes())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<u32, BoxError> {
+        Ok(std::str::from_utf8(bytes)?.parse()?)
+    }
+
+    #[test]
+    fn test_file_backend_round_trip() {
+        let dir = temp_dir("backend");
+        let backend = FileBackend::new(&dir).unwrap();
+
+        assert_eq!(backend.load("a/../b").unwrap(), None);
+        backend.save("a/../b", b"one").unwrap();
+        backend.save("a/../b", b"two").unwrap();
+        assert_eq!(backend.load("a/../b").unwrap(), Some(b"two".to_vec()));
+
+        // Only the escaped snapshot remains; no temp files or stray paths
+        let files: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(files, vec!["a%2F%2E%2E%2Fb.state"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_persistent_state_survives_restart() {
+        let dir = temp_dir("restart");
+        {
+            let manager = StateManager::with_persistence(FileBackend::new(&dir).unwrap());
+            let count = manager
+                .register_persistent_with("count", 1, encode, decode)
+                .unwrap();
+            assert_eq!(count.get(), 1);
+            count.set(5);
+            count.update(|count| *count += 1);
+        }
+
+        let manager = StateManager::with_persistence(FileBackend::new(&dir).unwrap());
+        let count = manager
+            .register_persistent_with("count", 1, encode, decode)
+            .unwrap();
+        assert_eq!(count.get(), 6);
+        manager.save().unwrap();
+
+        fs::write(dir.join("count.state"), "not a number").unwrap();
+        let error = manager
+            .register_persistent_with("count", 1, encode, decode)
+            .err()
+            .unwrap();
+        assert!(matches!(error, PersistError::Format { .. }));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_stale_save_is_dropped() {
+        let dir = temp_dir("stale");
+        let backend: Arc<dyn Persist> = Arc::new(FileBackend::new(&dir).unwrap());
+        let save = store("count", backend.clone(), encode);
+
+        // A slow writer's older value arrives after a newer one
+        save(&2, 2).unwrap();
+        save(&1, 1).unwrap();
+        assert_eq!(backend.load("count").unwrap(), Some(b"2".to_vec()));
+        save(&3, 3).unwrap();
+        assert_eq!(backend.load("count").unwrap(), Some(b"3".to_vec()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_persistence_requires_backend() {
+        let manager = StateManager::new();
+        let error = manager
+            .register_persistent_with("count", 1, encode, decode)
+            .err()
+            .unwrap();
+        assert_eq!(error.key(), "count");
+        assert!(matches!(error, PersistError::NoBackend { .. }));
+        assert!(manager.save().is_ok());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_register_persistent_with_serde() {
+        let dir = temp_dir("serde");
+        {
+            let manager = StateManager::with_persistence(FileBackend::new(&dir).unwrap());
+            let settings = manager
+                .register_persistent("settings", vec![String::from("dark")])
+                .unwrap();
+            settings.update(|settings| settings.push(String::from("compact")));
+        }
+
+        let manager = StateManager::with_persistence(FileBackend::new(&dir).unwrap());
+        let settings = manager
+            .register_persistent::<Vec<String>>("settings", Vec::new())
+            .unwrap();
+        assert_eq!(settings.get(), vec!["dark", "compact"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
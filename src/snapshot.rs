@@ -0,0 +1,470 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+
+use serde::de::{self, DeserializeOwned};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+
+use crate::batch;
+use crate::lock;
+use crate::{AsKey, Prepared, RegisterError, State, StateManager, Veto};
+
+// Type-erased JSON conversion for one registered state
+pub(crate) trait Codec: Send + Sync {
+    // Type tag recorded in snapshots
+    fn tag(&self) -> &'static str;
+
+    fn encode(&self) -> Result<Value, serde_json::Error>;
+
+    // Decodes without touching the state; the result is applied later
+    fn decode(&self, value: Value) -> Result<Box<dyn Decoded>, serde_json::Error>;
+}
+
+// A decoded value waiting to be written to its state
+pub(crate) trait Decoded {
+    fn address(&self) -> usize;
+    fn prepare(&mut self) -> Result<Box<dyn Prepared + '_>, Veto>;
+}
+
+struct DecodedState<T> {
+    state: State<T>,
+    value: Option<T>,
+}
+
+impl<T: Send + Sync + 'static> Decoded for DecodedState<T> {
+    fn address(&self) -> usize {
+        lock::address(&self.state.inner)
+    }
+
+    fn prepare(&mut self) -> Result<Box<dyn Prepared + '_>, Veto> {
+        let value = self.value.take().expect("prepared once");
+        self.state.prepare_set(value)
+    }
+}
+
+struct StateCodec<T> {
+    state: State<T>,
+    tag: &'static str,
+}
+
+impl<T> Codec for StateCodec<T>
+where
+    T: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+    fn tag(&self) -> &'static str {
+        self.tag
+    }
+
+    fn encode(&self) -> Result<Value, serde_json::Error> {
+        self.state.with(|value| serde_json::to_value(value))
+    }
+
+    fn decode(&self, value: Value) -> Result<Box<dyn Decoded>, serde_json::Error> {
+        let value: T = serde_json::from_value(value)?;
+        Ok(Box::new(DecodedState {
+            state: self.state.clone(),
+            value: Some(value),
+        }))
+    }
+}
+
+/// Every serializable state of a `StateManager`, keyed by registry key and
+/// tagged with its type. Serialize it with any serde format, or use
+/// `to_json`/`from_json`.
+///
+/// Tags default to `std::any::type_name`, which may change between compiler
+/// versions, so a snapshot kept across toolchain upgrades can fail to restore
+/// with `TypeMismatch`. Register such states with
+/// `StateManager::register_serializable_tagged` to use a stable tag instead.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    states: BTreeMap<String, Tagged>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Tagged {
+    type_name: String,
+    value: Value,
+}
+
+// Written as `{ "<key>": { "type": "<type name>", "value": ... }, ... }`
+impl Serialize for Snapshot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut states = Map::new();
+        for (key, tagged) in &self.states {
+            let mut entry = Map::new();
+            entry.insert("type".into(), Value::String(tagged.type_name.clone()));
+            entry.insert("value".into(), tagged.value.clone());
+            states.insert(key.clone(), Value::Object(entry));
+        }
+        Value::Object(states).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Snapshot {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let states = BTreeMap::<String, Map<String, Value>>::deserialize(deserializer)?;
+        let states = states
+            .into_iter()
+            .map(|(key, mut entry)| {
+                let Some(Value::String(type_name)) = entry.remove("type") else {
+                    return Err(de::Error::custom(format!(
+                        "state `{}` has no type tag",
+                        key
+                    )));
+                };
+                let value = entry
+                    .remove("value")
+                    .ok_or_else(|| de::Error::custom(format!("state `{}` has no value", key)))?;
+                Ok((key, Tagged { type_name, value }))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Snapshot { states })
+    }
+}
+
+impl Snapshot {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("JSON values always serialize")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, SnapshotError> {
+        serde_json::from_str(json).map_err(SnapshotError::Parse)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.states.keys().map(String::as_str)
+    }
+
+    /// The type tag recorded for `key`.
+    pub fn type_name(&self, key: &str) -> Option<&str> {
+        self.states.get(key).map(|tagged| tagged.type_name.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// The state was registered without serde support; use
+    /// `StateManager::register_serializable`.
+    NotSerializable {
+        key: String,
+        type_name: &'static str,
+    },
+    /// The snapshot holds a key that is not registered.
+    UnknownKey { key: String },
+    /// The snapshot was taken of a different type than the one registered.
+    TypeMismatch {
+        key: String,
+        expected: &'static str,
+        found: String,
+    },
+    /// The value could not be converted to or from JSON.
+    Format {
+        key: String,
+        error: serde_json::Error,
+    },
+    /// `Snapshot::from_json` was given invalid input.
+    Parse(serde_json::Error),
+    /// Middleware on the state refused the restored value.
+    Vetoed { key: String, veto: Veto },
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::NotSerializable { key, type_name } => write!(
+                f,
+                "state `{}` of type `{}` was not registered as serializable",
+                key, type_name
+            ),
+            SnapshotError::UnknownKey { key } => {
+                write!(f, "snapshot contains unregistered state `{}`", key)
+            }
+            SnapshotError::TypeMismatch {
+                key,
+                expected,
+                found,
+            } => write!(
+                f,
+                "state `{}` is registered as `{}` but the snapshot holds `{}`",
+                key, expected, found
+            ),
+            SnapshotError::Format { key, error } => {
+                write!(f, "state `{}` could not be converted: {}", key, error)
+            }
+            SnapshotError::Parse(error) => write!(f, "invalid snapshot: {}", error),
+            SnapshotError::Vetoed { key, veto } => {
+                write!(f, "restoring state `{}` was vetoed: {}", key, veto.reason())
+            }
+        }
+    }
+}
+
+impl Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SnapshotError::Format { error, .. } | SnapshotError::Parse(error) => Some(error),
+            SnapshotError::Vetoed { veto, .. } => Some(veto),
+            _ => None,
+        }
+    }
+}
+
+impl StateManager {
+    /// Like `register`, but the state can be included in `snapshot` and
+    /// `restore`.
+    pub fn register_serializable<T>(
+        &self,
+        key: impl AsKey<T>,
+        initial: T,
+    ) -> Result<State<T>, RegisterError>
+    where
+        T: Clone + Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        self.register_serializable_tagged(key, std::any::type_name::<T>(), initial)
+    }
+
+    /// Like `register_serializable`, but records `tag` instead of the type
+    /// name in snapshots, so they keep restoring across compiler versions
+    /// and type renames.
+    pub fn register_serializable_tagged<T>(
+        &self,
+        key: impl AsKey<T>,
+        tag: &'static str,
+        initial: T,
+    ) -> Result<State<T>, RegisterError>
+    where
+        T: Clone + Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        let key = key.as_key();
+        let state = self.register(key, initial)?;
+        self.attach_codec(key, &state, tag);
+        Ok(state)
+    }
+
+    pub(crate) fn attach_codec<T>(&self, key: &str, state: &State<T>, tag: &'static str)
+    where
+        T: Serialize + DeserializeOwned + Send + Sync + 'static,
+    {
+        if let Some(entry) = lock::write(&self.states).get_mut(key) {
+            let codec = StateCodec {
+                state: state.clone(),
+                tag,
+            };
+            entry.codec = Some(Box::new(codec));
+        }
+    }
+
+    /// Captures every registered state. Fails if any of them was registered
+    /// without serde support.
+    pub fn snapshot(&self) -> Result<Snapshot, SnapshotError> {
+        let states = lock::read(&self.states);
+        let mut snapshot = BTreeMap::new();
+        for (key, entry) in states.iter() {
+            let Some(codec) = &entry.codec else {
+                return Err(SnapshotError::NotSerializable {
+                    key: key.clone(),
+                    type_name: entry.type_name,
+                });
+            };
+            let value = codec.encode().map_err(|error| SnapshotError::Format {
+                key: key.clone(),
+                error,
+            })?;
+            let tagged = Tagged {
+                type_name: codec.tag().to_string(),
+                value,
+            };
+            snapshot.insert(key.clone(), tagged);
+        }
+        Ok(Snapshot { states: snapshot })
+    }
+
+    /// Sets every state in `snapshot` back to its captured value. Nothing is
+    /// changed unless every key is registered with a matching type, every
+    /// value decodes and no middleware vetoes it; subscribers run once all
+    /// states are set. States missing from the snapshot are left alone.
+    pub fn restore(&self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        let mut apply = Vec::with_capacity(snapshot.states.len());
+        {
+            let states = lock::read(&self.states);
+            for (key, tagged) in &snapshot.states {
+                let Some(entry) = states.get(key) else {
+                    return Err(SnapshotError::UnknownKey { key: key.clone() });
+                };
+                let Some(codec) = &entry.codec else {
+                    return Err(SnapshotError::NotSerializable {
+                        key: key.clone(),
+                        type_name: entry.type_name,
+                    });
+                };
+                if codec.tag() != tagged.type_name {
+                    return Err(SnapshotError::TypeMismatch {
+                        key: key.clone(),
+                        expected: codec.tag(),
+                        found: tagged.type_name.clone(),
+                    });
+                }
+                let decoded =
+                    codec
+                        .decode(tagged.value.clone())
+                        .map_err(|error| SnapshotError::Format {
+                            key: key.clone(),
+                            error,
+                        })?;
+                apply.push((key, decoded));
+            }
+        }
+
+        // Every state is locked, in address order like `ManagerTransaction`,
+        // before any is written, so a veto leaves all of them untouched
+        apply.sort_by_key(|(_, decoded)| decoded.address());
+        let mut prepared = Vec::with_capacity(apply.len());
+        for (key, decoded) in apply.iter_mut() {
+            let state = decoded.prepare().map_err(|veto| SnapshotError::Vetoed {
+                key: key.to_string(),
+                veto,
+            })?;
+            prepared.push(state);
+        }
+        for state in prepared.iter_mut() {
+            state.write();
+        }
+        let notifications: Vec<_> = prepared.into_iter().map(|state| state.finish()).collect();
+
+        batch::batch(|| notifications.into_iter().for_each(|notify| notify()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let manager = StateManager::new();
+        let count = manager.register_serializable("count", 1u32).unwrap();
+        let names = manager
+            .register_serializable("names", vec![String::from("ada")])
+            .unwrap();
+
+        let json = manager.snapshot().unwrap().to_json();
+        count.set(5);
+        names.update(|names| names.clear());
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let observed = names.clone();
+        let _subscription = count.subscribe(move |count| {
+            sink.lock().unwrap().push((*count, observed.get().len()));
+        });
+
+        let snapshot = Snapshot::from_json(&json).unwrap();
+        assert_eq!(snapshot.type_name("count"), Some("u32"));
+        manager.restore(&snapshot).unwrap();
+        assert_eq!(count.get(), 1);
+        assert_eq!(names.get(), vec!["ada"]);
+        // Subscribers only run once every state is restored
+        assert_eq!(*seen.lock().unwrap(), vec![(1, 1)]);
+    }
+
+    #[test]
+    fn test_restore_reports_type_mismatch() {
+        let source = StateManager::new();
+        source.register_serializable("count", 1u32).unwrap();
+        source
+            .register_serializable("label", String::from("old"))
+            .unwrap();
+        let snapshot = source.snapshot().unwrap();
+
+        let target = StateManager::new();
+        let label = target
+            .register_serializable("label", String::from("new"))
+            .unwrap();
+        let error = target.restore(&snapshot).unwrap_err();
+        assert!(matches!(error, SnapshotError::UnknownKey { .. }));
+
+        target.register_serializable("count", 1i64).unwrap();
+        let error = target.restore(&snapshot).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "state `count` is registered as `i64` but the snapshot holds `u32`"
+        );
+        // Nothing was applied
+        assert_eq!(label.get(), "new");
+    }
+
+    #[test]
+    fn test_stable_tags() {
+        let source = StateManager::new();
+        source
+            .register_serializable_tagged("count", "app.count", 7u32)
+            .unwrap();
+        let json = source.snapshot().unwrap().to_json();
+        let snapshot = Snapshot::from_json(&json).unwrap();
+        assert_eq!(snapshot.type_name("count"), Some("app.count"));
+
+        // The tag, not the Rust type name, has to match
+        let target = StateManager::new();
+        let count = target
+            .register_serializable_tagged("count", "app.count", 0u64)
+            .unwrap();
+        target.restore(&snapshot).unwrap();
+        assert_eq!(count.get(), 7);
+
+        let untagged = StateManager::new();
+        untagged.register_serializable("count", 0u32).unwrap();
+        let error = untagged.restore(&snapshot).unwrap_err();
+        assert!(matches!(
+            error,
+            SnapshotError::TypeMismatch {
+                expected: "u32",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_restore_reports_veto() {
+        use crate::Mutation;
+
+        let manager = StateManager::new();
+        let a = manager.register_serializable("a", 1).unwrap();
+        let b = manager.register_serializable("b", 1).unwrap();
+        let snapshot = manager.snapshot().unwrap();
+        a.set(2);
+        b.set(2);
+        b.add_middleware(|_: Mutation, current: &i32, next: &mut i32| {
+            if *next < *current {
+                return Err(Veto::new("b only grows"));
+            }
+            Ok(())
+        });
+
+        let error = manager.restore(&snapshot).unwrap_err();
+        assert!(matches!(&error, SnapshotError::Vetoed { key, .. } if key == "b"));
+        assert_eq!(
+            error.to_string(),
+            "restoring state `b` was vetoed: b only grows"
+        );
+        // `a` was not restored either, whichever state was locked first
+        assert_eq!((a.get(), b.get()), (2, 2));
+    }
+
+    #[test]
+    fn test_snapshot_requires_serializable_states() {
+        let manager = StateManager::new();
+        manager.register("plain", 0).unwrap();
+        let error = manager.snapshot().unwrap_err();
+        assert!(matches!(
+            error,
+            SnapshotError::NotSerializable {
+                type_name: "i32",
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,247 @@
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::lock;
+use crate::middleware::{self, Mutation, WriteError};
+use crate::{State, StatiaError, Subscription};
+
+/// State that only changes through actions. Every `dispatch` runs the reducer
+/// on the current value and records the action, so the log can be replayed
+/// from the same starting value to reproduce a session exactly.
+pub struct Store<S, A> {
+    state: State<S>,
+    reducer: fn(&mut S, A),
+    log: Arc<Mutex<ActionLog<S, A>>>,
+    pub(crate) middleware: middleware::Chain<S, A>,
+}
+
+// The actions dispatched since `base` was the store's value
+struct ActionLog<S, A> {
+    base: S,
+    actions: Vec<A>,
+}
+
+impl<S, A> Clone for Store<S, A> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            reducer: self.reducer,
+            log: self.log.clone(),
+            middleware: self.middleware.clone(),
+        }
+    }
+}
+
+impl<S, A> Store<S, A>
+where
+    S: Clone + Send + Sync + 'static,
+    A: Clone + Send + Sync + 'static,
+{
+    pub fn new(initial: S, reducer: fn(&mut S, A)) -> Self {
+        let log = ActionLog {
+            base: initial.clone(),
+            actions: Vec::new(),
+        };
+        Self {
+            state: State::new(initial),
+            reducer,
+            log: Arc::new(Mutex::new(log)),
+            middleware: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+
+    /// The underlying state, for reading and subscribing. Changing it
+    /// directly bypasses the reducer and the action log.
+    pub fn state(&self) -> &State<S> {
+        &self.state
+    }
+
+    pub fn get(&self) -> S {
+        self.state.get()
+    }
+
+    pub fn subscribe<F>(&self, callback: F) -> Subscription
+    where
+        F: Fn(&S) + Send + Sync + 'static,
+    {
+        self.state.subscribe(callback)
+    }
+
+    /// Runs the reducer with `action` and records it. Subscribers are
+    /// notified once the reducer returns. An action vetoed by middleware,
+    /// on the store or on its state, is dropped without being logged.
+    pub fn dispatch(&self, action: A) {
+        if let Err(WriteError::State(error)) = self.apply(action, false) {
+            panic!("Store::dispatch: {}", error);
+        }
+    }
+
+    /// Like `dispatch`, but reports vetoes and reducer panics the way
+    /// `State::try_update` does.
+    pub fn try_dispatch(&self, action: A) -> Result<(), StatiaError> {
+        Ok(self.apply(action, true)?)
+    }
+
+    fn apply(&self, mut action: A, strict: bool) -> Result<(), WriteError> {
+        let reducer = self.reducer;
+        let chain = middleware::snapshot(&self.middleware);
+        self.state.modify_then(
+            Mutation::Dispatch,
+            strict,
+            |state| {
+                middleware::run(&chain, Mutation::Dispatch, state, &mut action)?;
+                let logged = action.clone();
+                reducer(state, action);
+                Ok(logged)
+            },
+            // Only once the state's own middleware accepted the result, and
+            // still under its write lock, so the log order is the order the
+            // reducer saw
+            |logged| lock::acquire(&self.log).actions.push(logged),
+        )
+    }
+
+    /// Every action dispatched since the store was created or last
+    /// checkpointed, oldest first.
+    pub fn actions(&self) -> Vec<A> {
+        lock::acquire(&self.log).actions.clone()
+    }
+
+    /// The value the action log starts from.
+    pub fn base(&self) -> S {
+        lock::acquire(&self.log).base.clone()
+    }
+
+    /// Makes the current value the new starting point of the log and clears
+    /// it, bounding its size for long-running stores.
+    pub fn checkpoint(&self) {
+        // Dispatches log and reduce under the write lock, so holding the read
+        // lock keeps the value and the log in step
+        self.state.with_locked(|value| {
+            let mut log = lock::acquire(&self.log);
+            log.base = value.clone();
+            log.actions.clear();
+        });
+    }
+
+    /// Resets the store to the log's starting value and reduces `actions` in
+    /// order, replacing the log with them. Subscribers see only the final
+    /// value. Replaying a store's own `actions()` into a fresh store built
+    /// from the same `base()` reproduces its current value. If middleware on
+    /// the state vetoes the result, neither the value nor the log changes.
+    pub fn replay(&self, actions: impl IntoIterator<Item = A>) {
+        let reducer = self.reducer;
+        let result = self.state.modify_then(
+            Mutation::Dispatch,
+            false,
+            |state| {
+                *state = self.base();
+                let mut replayed = Vec::new();
+                for action in actions {
+                    replayed.push(action.clone());
+                    reducer(state, action);
+                }
+                Ok(replayed)
+            },
+            |replayed| lock::acquire(&self.log).actions = replayed,
+        );
+        if let Err(WriteError::State(error)) = result {
+            panic!("Store::replay: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    enum Action {
+        Add(i64),
+        Double,
+        Reset,
+    }
+
+    fn reduce(total: &mut i64, action: Action) {
+        match action {
+            Action::Add(amount) => *total += amount,
+            Action::Double => *total *= 2,
+            Action::Reset => *total = 0,
+        }
+    }
+
+    #[test]
+    fn test_dispatch_logs_actions() {
+        let store = Store::new(1, reduce);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let _subscription = store.subscribe(move |total| sink.lock().unwrap().push(*total));
+
+        store.dispatch(Action::Add(2));
+        store.dispatch(Action::Double);
+        assert_eq!(store.get(), 6);
+        assert_eq!(store.actions(), vec![Action::Add(2), Action::Double]);
+        assert_eq!(*seen.lock().unwrap(), vec![3, 6]);
+    }
+
+    #[test]
+    fn test_replay_reproduces_state() {
+        let store = Store::new(1, reduce);
+        store.dispatch(Action::Add(4));
+        store.dispatch(Action::Double);
+        store.dispatch(Action::Add(-3));
+
+        let reproduction = Store::new(store.base(), reduce);
+        reproduction.replay(store.actions());
+        assert_eq!(reproduction.get(), store.get());
+        assert_eq!(reproduction.actions(), store.actions());
+
+        // Replaying starts over from the base rather than the current value
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let _subscription = store.subscribe(move |total| sink.lock().unwrap().push(*total));
+        store.replay([Action::Add(1), Action::Double]);
+        assert_eq!(store.get(), 4);
+        assert_eq!(*seen.lock().unwrap(), vec![4]);
+    }
+
+    #[test]
+    fn test_checkpoint_moves_base() {
+        let store = Store::new(0, reduce);
+        store.dispatch(Action::Add(5));
+        store.checkpoint();
+        assert_eq!(store.base(), 5);
+        assert!(store.actions().is_empty());
+
+        store.dispatch(Action::Reset);
+        store.dispatch(Action::Add(2));
+        store.replay(store.actions());
+        assert_eq!(store.get(), 2);
+    }
+
+    #[test]
+    fn test_checkpoint_during_dispatches() {
+        let store = Store::new(0, reduce);
+        let writers: Vec<_> = (0..4)
+            .map(|_| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..500 {
+                        store.dispatch(Action::Add(1));
+                    }
+                })
+            })
+            .collect();
+        for _ in 0..200 {
+            store.checkpoint();
+        }
+        writers
+            .into_iter()
+            .for_each(|writer| writer.join().unwrap());
+
+        // No action is lost between the base and the log
+        let reproduction = Store::new(store.base(), reduce);
+        reproduction.replay(store.actions());
+        assert_eq!(reproduction.get(), 2000);
+        assert_eq!(store.get(), 2000);
+    }
+}
@@ -0,0 +1,195 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use crate::lock;
+use crate::{State, Subscription};
+
+// Shared between a subscription and the future or stream waiting on it
+#[derive(Default)]
+struct Signal {
+    changed: bool,
+    waker: Option<Waker>,
+}
+
+fn listen<T: Send + Sync + 'static>(state: &State<T>) -> (Arc<Mutex<Signal>>, Subscription) {
+    let signal = Arc::new(Mutex::new(Signal::default()));
+    let sink = signal.clone();
+    let subscription = state.subscribe(move |_| {
+        let waker = {
+            let mut signal = lock::acquire(&sink);
+            signal.changed = true;
+            signal.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    });
+    (signal, subscription)
+}
+
+// Consumes a pending change, or registers the waker for the next one
+fn poll_signal(signal: &Mutex<Signal>, cx: &mut Context<'_>) -> Poll<()> {
+    let mut signal = lock::acquire(signal);
+    if std::mem::take(&mut signal.changed) {
+        return Poll::Ready(());
+    }
+    match &mut signal.waker {
+        Some(waker) => waker.clone_from(cx.waker()),
+        None => signal.waker = Some(cx.waker().clone()),
+    }
+    Poll::Pending
+}
+
+/// Future returned by `State::changed`. Resolves once the state changes after
+/// the future was created.
+#[must_use = "futures do nothing unless polled"]
+pub struct Changed {
+    signal: Arc<Mutex<Signal>>,
+    _subscription: Subscription,
+}
+
+impl Future for Changed {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        poll_signal(&self.signal, cx)
+    }
+}
+
+/// Stream of values returned by `State::stream`. Changes made while the
+/// consumer is busy collapse into the latest value, so it never falls behind.
+#[must_use = "streams do nothing unless polled"]
+pub struct Changes<T> {
+    state: State<T>,
+    signal: Arc<Mutex<Signal>>,
+    _subscription: Subscription,
+}
+
+impl<T: Clone + Send + Sync + 'static> Changes<T> {
+    /// Polls for the next value; the stream never ends while it is alive.
+    pub fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        poll_signal(&self.signal, cx).map(|()| Some(self.state.get()))
+    }
+
+    /// Waits for the next value, e.g. `while let Some(value) = stream.next().await`.
+    // Mirrors `StreamExt::next`, not `Iterator::next`
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Next<'_, T> {
+        Next { changes: self }
+    }
+}
+
+/// Future returned by `Changes::next`.
+#[must_use = "futures do nothing unless polled"]
+pub struct Next<'a, T> {
+    changes: &'a mut Changes<T>,
+}
+
+impl<T: Clone + Send + Sync + 'static> Future for Next<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        Pin::new(&mut *self.changes).poll_next(cx)
+    }
+}
+
+#[cfg(feature = "futures-core")]
+impl<T: Clone + Send + Sync + 'static> futures_core::Stream for Changes<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        Changes::poll_next(self, cx)
+    }
+}
+
+impl<T: Send + Sync + 'static> State<T> {
+    /// Returns a future that resolves on the next change. Works with any
+    /// executor; nothing is spawned.
+    pub fn changed(&self) -> Changed {
+        let (signal, subscription) = listen(self);
+        Changed {
+            signal,
+            _subscription: subscription,
+        }
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> State<T> {
+    /// Returns a stream yielding the value after each change.
+    pub fn stream(&self) -> Changes<T> {
+        let (signal, subscription) = listen(self);
+        Changes {
+            state: self.clone(),
+            signal,
+            _subscription: subscription,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::task::Wake;
+    use std::thread;
+
+    struct Unpark(thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    // Minimal executor so the tests don't need an async runtime
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn test_changed_resolves_on_next_change() {
+        let state = State::new(0);
+        let changed = state.changed();
+
+        let writer = state.clone();
+        let handle = thread::spawn(move || writer.set(1));
+        block_on(changed);
+        handle.join().unwrap();
+
+        assert_eq!(state.get(), 1);
+        assert_eq!(state.subscriber_count(), 0);
+    }
+
+    #[test]
+    fn test_stream_collapses_missed_changes() {
+        let state = State::new(0);
+        let mut stream = state.stream();
+
+        state.set(1);
+        state.set(2);
+        assert_eq!(block_on(stream.next()), Some(2));
+
+        let (ready, wait_ready) = mpsc::channel();
+        let writer = state.clone();
+        let handle = thread::spawn(move || {
+            wait_ready.recv().unwrap();
+            writer.set(3);
+        });
+        let next = async {
+            ready.send(()).unwrap();
+            stream.next().await
+        };
+        assert_eq!(block_on(next), Some(3));
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+use crate::middleware::{Mutation, Veto, WriteError};
+use crate::{State, Transaction};
+
+/// Why `ValidatedState::set` or `update` left the value untouched.
+#[derive(Debug)]
+pub enum ValidationError<E> {
+    /// The validator rejected the change.
+    Invalid(E),
+    /// Other middleware on the state vetoed the change.
+    Vetoed(Veto),
+}
+
+impl<E: fmt::Display> fmt::Display for ValidationError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::Invalid(error) => write!(f, "invalid change: {}", error),
+            ValidationError::Vetoed(veto) => veto.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for ValidationError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ValidationError::Invalid(error) => Some(error),
+            ValidationError::Vetoed(veto) => Some(veto),
+        }
+    }
+}
+
+/// State whose changes must pass a validator. The validator runs as
+/// middleware, so it guards every clone of the state, transactions included;
+/// through this handle, `set` and `update` also return its error.
+pub struct ValidatedState<T, E> {
+    state: State<T>,
+    _error: PhantomData<fn() -> E>,
+}
+
+impl<T, E> Clone for ValidatedState<T, E> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            _error: PhantomData,
+        }
+    }
+}
+
+impl<T, E> ValidatedState<T, E>
+where
+    T: Clone + Send + Sync + 'static,
+    E: Error + Send + Sync + 'static,
+{
+    pub fn state(&self) -> &State<T> {
+        &self.state
+    }
+
+    /// Like `State::set`, but returns the validator's error, leaving the
+    /// value untouched, if it rejects `new_value`. Vetoes from other
+    /// middleware come back as `ValidationError::Vetoed`.
+    pub fn set(&self, new_value: T) -> Result<(), ValidationError<E>> {
+        Self::check(self.state.put(new_value, false))
+    }
+
+    /// Like `State::update`. The updater works on a copy, so a rejected
+    /// update leaves the value untouched.
+    pub fn update<F>(&self, updater: F) -> Result<(), ValidationError<E>>
+    where
+        F: FnOnce(&mut T),
+    {
+        Self::check(self.state.modify(Mutation::Update, false, |value| {
+            updater(value);
+            Ok(())
+        }))
+    }
+
+    /// Starts a transaction on this state. A rejected commit fails with
+    /// `CommitError::Vetoed`, whose `veto.reason()` downcasts to `E`.
+    pub fn transaction(&self) -> Transaction<T> {
+        Transaction::new(self.state.clone())
+    }
+
+    // Only the validator's own vetoes carry an `E`; anything else is
+    // passed on whole
+    fn check(result: Result<(), WriteError>) -> Result<(), ValidationError<E>> {
+        match result {
+            Ok(()) => Ok(()),
+            Err(WriteError::Vetoed(veto)) if veto.reason().is::<E>() => {
+                let error = veto.into_reason().downcast::<E>();
+                Err(ValidationError::Invalid(*error.expect("checked above")))
+            }
+            Err(WriteError::Vetoed(veto)) => Err(ValidationError::Vetoed(veto)),
+            Err(WriteError::State(error)) => panic!("ValidatedState: {}", error),
+        }
+    }
+}
+
+impl<T, E> Deref for ValidatedState<T, E> {
+    type Target = State<T>;
+
+    fn deref(&self) -> &State<T> {
+        &self.state
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> State<T> {
+    /// Rejects every change for which `validator(old, new)` fails, whichever
+    /// handle or transaction makes it. Returns a handle whose `set` and
+    /// `update` report the rejection; elsewhere it behaves like a veto from
+    /// `add_middleware`.
+    pub fn with_validator<E, F>(&self, validator: F) -> ValidatedState<T, E>
+    where
+        E: Error + Send + Sync + 'static,
+        F: Fn(&T, &T) -> Result<(), E> + Send + Sync + 'static,
+    {
+        self.add_middleware(move |_: Mutation, current: &T, pending: &mut T| {
+            validator(current, pending).map_err(Veto::new)
+        });
+        ValidatedState {
+            state: self.clone(),
+            _error: PhantomData,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CommitError;
+    use std::fmt;
+
+    #[derive(Debug, PartialEq)]
+    struct Overdrawn {
+        balance: i64,
+    }
+
+    impl fmt::Display for Overdrawn {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "balance would be {}", self.balance)
+        }
+    }
+
+    impl Error for Overdrawn {}
+
+    fn account(balance: i64) -> ValidatedState<i64, Overdrawn> {
+        State::new(balance).with_validator(|_, new: &i64| {
+            if *new < 0 {
+                return Err(Overdrawn { balance: *new });
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn test_validator_rejects_set_and_update() {
+        let balance = account(10);
+        assert!(matches!(
+            balance.set(-5),
+            Err(ValidationError::Invalid(Overdrawn { balance: -5 }))
+        ));
+        assert!(matches!(
+            balance.update(|balance| *balance -= 25),
+            Err(ValidationError::Invalid(Overdrawn { balance: -15 }))
+        ));
+        assert_eq!(balance.get(), 10);
+        assert_eq!(balance.version(), 0);
+
+        assert!(balance.update(|balance| *balance -= 4).is_ok());
+        assert_eq!(balance.get(), 6);
+    }
+
+    #[test]
+    fn test_foreign_veto_is_reported() {
+        let balance = account(10);
+        balance.add_middleware(|_: Mutation, current: &i64, next: &mut i64| {
+            if *next > *current + 100 {
+                return Err(Veto::new("deposit too large"));
+            }
+            Ok(())
+        });
+
+        let error = balance.set(500).unwrap_err();
+        assert!(matches!(error, ValidationError::Vetoed(_)));
+        assert_eq!(error.to_string(), "change vetoed: deposit too large");
+        assert!(balance.update(|balance| *balance += 200).is_err());
+        assert_eq!(balance.get(), 10);
+    }
+
+    #[test]
+    fn test_validator_rejects_commit() {
+        let balance = account(10);
+        let mut transaction = balance.transaction();
+        transaction.update(|balance| *balance -= 8);
+        transaction.update(|balance| *balance -= 8);
+        let Err(CommitError::Vetoed { veto, .. }) = transaction.commit() else {
+            panic!("commit should be rejected");
+        };
+        assert_eq!(
+            veto.reason().downcast_ref::<Overdrawn>(),
+            Some(&Overdrawn { balance: -6 })
+        );
+        assert_eq!(balance.get(), 10);
+    }
+
+    #[test]
+    fn test_validator_guards_other_handles() {
+        let balance = account(10);
+        let plain = balance.state().clone();
+
+        plain.set(-1);
+        assert_eq!(balance.get(), 10);
+        assert_eq!(
+            plain.try_set(-1).unwrap_err().to_string(),
+            "change vetoed: balance would be -1"
+        );
+    }
+}
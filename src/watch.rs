@@ -0,0 +1,136 @@
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::Duration;
+
+use crate::lock;
+use crate::{State, Subscription};
+
+// Number of changes seen by the subscription, and the condvar to wake on
+struct Version {
+    count: Mutex<u64>,
+    changed: Condvar,
+}
+
+/// Blocking receiver returned by `State::watch`. Each receive yields the
+/// latest value; changes that happened in between are skipped, so a slow
+/// thread never works through a backlog.
+pub struct Watcher<T> {
+    state: State<T>,
+    version: Arc<Version>,
+    seen: u64,
+    _subscription: Subscription,
+}
+
+impl<T: Clone + Send + Sync + 'static> Watcher<T> {
+    /// Blocks until the state changes, then returns the latest value.
+    pub fn recv(&mut self) -> T {
+        let count = lock::acquire(&self.version.count);
+        let count = self
+            .version
+            .changed
+            .wait_while(count, |count| *count == self.seen)
+            .unwrap_or_else(PoisonError::into_inner);
+        self.seen = *count;
+        drop(count);
+        self.state.get()
+    }
+
+    /// Like `recv`, but gives up after `timeout`.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Option<T> {
+        let count = lock::acquire(&self.version.count);
+        let (count, _) = self
+            .version
+            .changed
+            .wait_timeout_while(count, timeout, |count| *count == self.seen)
+            .unwrap_or_else(PoisonError::into_inner);
+        if *count == self.seen {
+            return None;
+        }
+        self.seen = *count;
+        drop(count);
+        Some(self.state.get())
+    }
+
+    /// Returns the latest value if the state changed since the last receive.
+    pub fn try_recv(&mut self) -> Option<T> {
+        let count = *lock::acquire(&self.version.count);
+        if count == self.seen {
+            return None;
+        }
+        self.seen = count;
+        Some(self.state.get())
+    }
+}
+
+impl<T> Watcher<T> {
+    /// Number of changes since the watcher was created, including ones not
+    /// received yet.
+    pub fn version(&self) -> u64 {
+        *lock::acquire(&self.version.count)
+    }
+
+    /// Whether a receive would return without blocking.
+    pub fn has_changed(&self) -> bool {
+        self.version() != self.seen
+    }
+}
+
+impl<T: Send + Sync + 'static> State<T> {
+    /// Returns a receiver that blocks the calling thread until the next change.
+    pub fn watch(&self) -> Watcher<T> {
+        let version = Arc::new(Version {
+            count: Mutex::new(0),
+            changed: Condvar::new(),
+        });
+        let sink = version.clone();
+        let subscription = self.subscribe(move |_| {
+            *lock::acquire(&sink.count) += 1;
+            sink.changed.notify_all();
+        });
+
+        Watcher {
+            state: self.clone(),
+            version,
+            seen: 0,
+            _subscription: subscription,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_watcher_collapses_missed_changes() {
+        let state = State::new(0);
+        let mut watcher = state.watch();
+        assert_eq!(watcher.try_recv(), None);
+
+        state.set(1);
+        state.set(2);
+        assert!(watcher.has_changed());
+        assert_eq!(watcher.version(), 2);
+        assert_eq!(watcher.try_recv(), Some(2));
+        assert_eq!(watcher.try_recv(), None);
+        assert_eq!(watcher.recv_timeout(Duration::from_millis(10)), None);
+    }
+
+    #[test]
+    fn test_recv_blocks_until_change() {
+        let state = State::new(String::new());
+        let mut watcher = state.watch();
+
+        let writer = state.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            writer.set(String::from("ready"));
+        });
+
+        assert_eq!(watcher.recv(), "ready");
+        handle.join().unwrap();
+
+        drop(watcher);
+        assert_eq!(state.subscriber_count(), 0);
+    }
+}